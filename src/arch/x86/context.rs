@@ -1,5 +1,6 @@
 use core::mem::size_of;
 use alloc::boxed::Box;
+use super::syscall::SyscallFrame;
 
 pub const DEFAULT_KSTACK_PAGES: usize = 2;
 
@@ -9,29 +10,62 @@ pub const DEFAULT_KSTACK_PAGES: usize = 2;
 struct InnerContext {
     rsp0:       usize,      // 0x00
     rsp0_top:   usize,      // 0x08
+    cr3:        usize,      // 0x10
 }
 
+// `inner` has to be the first field, `Thread` relies
+// on that too
+#[repr(C)]
 pub struct Context {
     inner: InnerContext,
     kstack: Box<[u8]>,
-    ustack: Box<[u8]>,
 }
 
 impl Context {
-    pub fn new(entry: usize) -> Context {
-        let mut ctx = Context {
+    fn empty(cr3: usize) -> Context {
+        Context {
             // Will be initialized a bit later
             inner: InnerContext {
                 rsp0: 0,
                 rsp0_top: 0,
+                cr3,
             },
             kstack: Box::new([0u8; DEFAULT_KSTACK_PAGES * 0x1000]),
-            ustack: Box::new([0u8; 2 * 0x1000]),
-        };
-        ctx.setup(entry);
+        }
+    }
+
+    /// Context running in ring 0 on its kernel stack
+    pub fn new_kernel(entry: usize, cr3: usize) -> Context {
+        let mut ctx = Context::empty(cr3);
+        let top = ctx.kstack.as_ptr() as usize + ctx.kstack.len();
+        ctx.setup(entry, 0x08, 0x10, top, 0x200, None, &[0; 6]);
+        ctx
+    }
+
+    /// Context entering ring 3 at `entry` with user stack at `ustack_top`
+    pub fn new_user(entry: usize, ustack_top: usize, cr3: usize) -> Context {
+        let mut ctx = Context::empty(cr3);
+        ctx.setup(entry, 0x23, 0x1B, ustack_top, 0x200, None, &[0; 6]);
         ctx
     }
 
+    /// Context returning to ring 3 from a syscall described by
+    /// `frame` with zero result, i.e. a child of `fork()`. All
+    /// registers but rax keep their values from `frame`
+    pub fn new_fork(frame: &SyscallFrame, cr3: usize) -> Context {
+        let mut ctx = Context::empty(cr3);
+        ctx.setup(frame.rip, 0x23, 0x1B, frame.rsp, frame.rflags, Some(&frame.args()),
+                  &[frame.r15, frame.r14, frame.r13, frame.r12, frame.rbp, frame.rbx]);
+        ctx
+    }
+
+    /// Registers saved on entry to the syscall being executed
+    /// by this context's thread
+    pub fn syscall_frame(&self) -> &SyscallFrame {
+        let addr = self.inner.rsp0_top - size_of::<SyscallFrame>();
+        unsafe { &*(addr as *const SyscallFrame) }
+    }
+
     unsafe fn push(&mut self, val: usize) {
         let base = self.kstack.as_mut_ptr() as usize;
         if self.inner.rsp0 <= base {
@@ -42,10 +76,13 @@ impl Context {
         *ptr = val;
     }
 
-    fn setup(&mut self, entry: usize) {
+    /// Prepares the stack for the first switch to the context.
+    /// `args` are values for rdi, rsi, rdx, r10, r8 and r9 (zero
+    /// if None), `saved` are values for r15, r14, r13, r12, rbp and rbx
+    fn setup(&mut self, entry: usize, cs: usize, ss: usize, rsp: usize,
+             rflags: usize, args: Option<&[usize; 6]>, saved: &[usize; 6]) {
         // Setup initial rsp0 and rsp0_top
         let base = self.kstack.as_mut_ptr() as usize;
-        let ustack_base = self.ustack.as_mut_ptr() as usize;
         let top = base + self.kstack.len();
 
         self.inner.rsp0 = top;
@@ -55,21 +92,25 @@ impl Context {
 
         unsafe {
             // Context for iret entry
-            self.push(0x1B);    // ss
-            self.push(ustack_base + 2 * 0x1000);  // user rsp
-            self.push(0x200);     // rflags
-            self.push(0x23);    // cs
+            self.push(ss);
+            self.push(rsp);
+            self.push(rflags);
+            self.push(cs);
             self.push(entry);   // rip
 
             // Context for common switching
-            self.push(context_entry_iret as usize);
-
-            self.push(0);       // r15
-            self.push(0);       // r14
-            self.push(0);       // r13
-            self.push(0);       // r12
-            self.push(0);       // rbp
-            self.push(0);       // rbx
+            if let Some(args) = args {
+                for &reg in args.iter() {
+                    self.push(reg);
+                }
+                self.push(context_entry_fork as usize);
+            } else {
+                self.push(context_entry_iret as usize);
+            }
+
+            for &reg in saved.iter() {
+                self.push(reg);
+            }
         }
     }
 
@@ -86,17 +127,66 @@ extern "C" {
     fn context_switch_to(dst: &mut InnerContext);
     fn context_switch(dst: &mut InnerContext, src: &mut InnerContext);
     fn context_entry_iret();
+    fn context_entry_fork();
 }
 
 global_asm!(r#"
 .type context_entry_iret, %function
+.type context_entry_fork, %function
 .type context_switch, %function
 .type context_switch_to, %function
 context_entry_iret:
+    // First switch to the thread, finish it on this side.
+    // %rsp points to the iret frame here, keep the stack
+    // 16-byte aligned for the call
+    sub $8, %rsp
+    call sched_finish_switch
+    add $8, %rsp
+
+    // Don't leak kernel values to the new context
+    xor %rax, %rax
+    xor %rcx, %rcx
+    xor %rdx, %rdx
+    xor %rsi, %rsi
+    xor %rdi, %rdi
+    xor %r8, %r8
+    xor %r9, %r9
+    xor %r10, %r10
+    xor %r11, %r11
+
+.Lcontext_iret:
+    // Entering ring 3, restore user %gs
+    testb $3, 0x08(%rsp)
+    jz 1f
+    swapgs
+1:
     iretq
 
 .size context_entry_iret, . - context_entry_iret
 
+context_entry_fork:
+    // Same as above, but argument registers of the syscall
+    // are on the stack too
+    sub $8, %rsp
+    call sched_finish_switch
+    add $8, %rsp
+
+    pop %r9
+    pop %r8
+    pop %r10
+    pop %rdx
+    pop %rsi
+    pop %rdi
+
+    // fork() returns zero in the child, %rcx and %r11 hold
+    // rip and rflags just like after sysret
+    xor %rax, %rax
+    mov 0x00(%rsp), %rcx
+    mov 0x10(%rsp), %r11
+    jmp .Lcontext_iret
+
+.size context_entry_fork, . - context_entry_fork
+
 context_switch:
     // Push callee-saved context
     push %r15
@@ -119,11 +209,21 @@ context_switch_to:
     pop %r14
     pop %r15
 
-    // TODO: cr3
-
-    // Load new TSS.RSP0 value <- top of kernel stack
+    // Only reload CR3 when switching to a different space,
+    // threads of the same process don't need a TLB flush
+    mov 0x10(%rdi), %rax
+    mov %cr3, %rcx
+    cmp %rax, %rcx
+    je 1f
+    mov %rax, %cr3
+1:
+
+    // Top of kernel stack goes to per-CPU block for
+    // syscall entry and TSS.RSP0 for interrupts
     mov 0x08(%rdi), %rax
-    mov %rax, (TSS+4)(%rip)
+    mov %rax, %gs:0x10
+    mov %gs:0x20, %rcx
+    mov %rax, 4(%rcx)
 
     ret
 .size context_switch, . - context_switch
@@ -0,0 +1,78 @@
+//! Per-CPU data. While in kernel mode %gs points to the
+//! CPU's block, user %gs is kept in `IA32_KERNEL_GS_BASE`
+//! and the two are exchanged with `swapgs` on every
+//! transition between the rings
+
+use super::gdt::{Tss, TSS};
+use super::regs::{self, MSR_IA32_GS_BASE, MSR_IA32_KERNEL_GS_BASE};
+use crate::thread::Thread;
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+
+// Fields are accessed from assembly using
+// well-known offsets
+#[repr(C)]
+pub struct Cpu {
+    this:           *mut Cpu,       // 0x00
+    pub current:    *mut Thread,    // 0x08
+    /// Top of the current thread's kernel stack
+    kernel_stack:   usize,          // 0x10
+    /// User %rsp while entering a syscall
+    scratch:        usize,          // 0x18
+    tss:            *mut Tss,       // 0x20
+    pub id:         u32,            // 0x28
+    /// Runs when there's nothing else to do
+    pub idle:       *mut Thread,
+    /// Thread switched away from, see `thread::sched_finish_switch`
+    pub previous:   *mut Thread,
+}
+
+static mut BSP: Cpu = Cpu::new();
+
+impl Cpu {
+    const fn new() -> Cpu {
+        Cpu {
+            this: null_mut(),
+            current: null_mut(),
+            kernel_stack: 0,
+            scratch: 0,
+            tss: null_mut(),
+            id: 0,
+            idle: null_mut(),
+            previous: null_mut(),
+        }
+    }
+}
+
+/// Per-CPU block of the calling CPU
+#[inline(always)]
+pub fn this() -> &'static mut Cpu {
+    let ptr: *mut Cpu;
+    unsafe {
+        llvm_asm!("mov %gs:0, $0":"=r"(ptr));
+        &mut *ptr
+    }
+}
+
+/// Makes `cpu` the per-CPU block of the calling CPU
+pub unsafe fn init(cpu: &'static mut Cpu, id: u32, tss: *mut Tss) {
+    cpu.this = cpu;
+    cpu.id = id;
+    cpu.tss = tss;
+
+    regs::wrmsr(MSR_IA32_GS_BASE, cpu.this as u64);
+    regs::wrmsr(MSR_IA32_KERNEL_GS_BASE, 0);
+}
+
+pub fn init_bsp() {
+    unsafe {
+        init(&mut BSP, 0, &mut TSS);
+    }
+}
+
+/// Allocates a per-CPU block for an application processor
+pub fn init_ap(id: u32, tss: *mut Tss) {
+    unsafe {
+        init(Box::leak(Box::new(Cpu::new())), id, tss);
+    }
+}
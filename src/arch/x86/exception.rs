@@ -1,4 +1,6 @@
 use crate::arch::x86::{regs, intrinsics};
+use crate::mem::region::{self, Access};
+use crate::thread;
 
 #[repr(C)]
 struct Context {
@@ -61,8 +63,65 @@ fn dump_context(ctx: &Context) {
     print!("\x1b[0m");
 }
 
+/// Kernel code range which is allowed to fault, execution
+/// resumes at `fixup` if it does
+#[derive(Clone, Copy)]
+struct Fixup {
+    start:  usize,
+    end:    usize,
+    fixup:  usize,
+}
+
+const MAX_FIXUPS: usize = 16;
+static mut FIXUPS: [Fixup; MAX_FIXUPS] = [Fixup { start: 0, end: 0, fixup: 0 }; MAX_FIXUPS];
+static mut FIXUP_COUNT: usize = 0;
+
+/// Registers `start .. end` as code which may fault (e.g. when
+/// accessing user memory) and the address to continue at
+pub fn add_fixup(start: usize, end: usize, fixup: usize) {
+    unsafe {
+        assert!(FIXUP_COUNT < MAX_FIXUPS);
+        FIXUPS[FIXUP_COUNT] = Fixup { start, end, fixup };
+        FIXUP_COUNT += 1;
+    }
+}
+
+fn find_fixup(rip: usize) -> Option<usize> {
+    unsafe { &FIXUPS[.. FIXUP_COUNT] }.iter()
+        .find(|f| rip >= f.start && rip < f.end)
+        .map(|f| f.fixup)
+}
+
+fn page_fault(ctx: &Context) -> bool {
+    let addr = regs::cr2::read();
+    let access = if ctx.exc_code & (1 << 4) != 0 {
+        Access::Execute
+    } else if ctx.exc_code & (1 << 1) != 0 {
+        Access::Write
+    } else {
+        Access::Read
+    };
+
+    region::handle_fault(addr, access, ctx.exc_code & (1 << 0) != 0)
+}
+
 #[no_mangle]
 fn exception_handler(ctx: &mut Context) {
+    if ctx.exc_no == 14 && page_fault(ctx) {
+        return;
+    }
+
+    if ctx.cs & 3 == 3 {
+        // Illegal access from userspace, only kill the thread
+        dump_context(ctx);
+        unsafe { thread::exit_current(); }
+    }
+
+    if let Some(fixup) = find_fixup(ctx.rip as usize) {
+        ctx.rip = fixup as u64;
+        return;
+    }
+
     dump_context(ctx);
     intrinsics::halt();
 }
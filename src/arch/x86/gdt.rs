@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use core::mem::size_of;
+use alloc::boxed::Box;
 
 #[repr(packed)]
 struct Entry64 {
@@ -63,7 +64,7 @@ const ACC_PR: u8 = 1 << 7;
 const FLAG_LONG: u8 = 1 << 5;
 
 const ENTRY_COUNT: usize = 7;
-static mut ENTRIES: [Entry; ENTRY_COUNT] = [
+const ENTRIES_INIT: [Entry; ENTRY_COUNT] = [
     Entry::new(0, 0, 0, 0),                             // global null 0x00
     Entry::new(0, 0, FLAG_LONG,                         // kernel code 0x08
                      ACC_PR | ACC_S | ACC_EX),
@@ -76,8 +77,7 @@ static mut ENTRIES: [Entry; ENTRY_COUNT] = [
     Entry::new(0, 0, 0, 0),                             // Empty TSS
     Entry::new(0, 0, 0, 0),                             // Empty TSS
 ];
-#[no_mangle]
-pub static mut TSS: Tss = Tss {
+const TSS_INIT: Tss = Tss {
     _res0:      0,
     rsp0:       0,
     rsp1:       0,
@@ -94,6 +94,11 @@ pub static mut TSS: Tss = Tss {
     _res3:      0,
     iopb_base:  0
 };
+
+// Bootstrap processor's tables, APs allocate their own
+static mut ENTRIES: [Entry; ENTRY_COUNT] = ENTRIES_INIT;
+#[no_mangle]
+pub static mut TSS: Tss = TSS_INIT;
 static mut POINTER: Pointer = Pointer {
     offset: 0,
     size: 0
@@ -105,19 +110,36 @@ extern "C" {
 }
 global_asm!(include_str!("gdt_s.S"));
 
+unsafe fn load(entries: &mut [Entry; ENTRY_COUNT], tss: &Tss, pointer: &mut Pointer) {
+    let tss_ptr = tss as *const _ as usize;
+    entries[ENTRY_COUNT - 2] = Entry::new((tss_ptr & 0xFFFFFFFF) as u32,
+                                          (size_of::<Tss>() - 1) as u32,
+                                          FLAG_LONG,
+                                          ACC_PR | ACC_AC | ACC_EX);
+    let tss_upper = &mut entries[ENTRY_COUNT - 1] as *mut _ as *mut u64;
+    *tss_upper = ((tss_ptr >> 32) & 0xFFFFFFFF) as u64;
+
+    pointer.offset = entries.as_ptr() as usize;
+    pointer.size = (ENTRY_COUNT * size_of::<Entry>() - 1) as u16;
+    load_gdt(pointer);
+}
+
 pub fn init() {
-    // TODO: TSS
     unsafe {
-        let tss_ptr = &TSS as *const _ as usize;
-        ENTRIES[ENTRY_COUNT - 2] = Entry::new((tss_ptr & 0xFFFFFFFF) as u32,
-                                              (size_of::<Tss>() - 1) as u32,
-                                              FLAG_LONG,
-                                              ACC_PR | ACC_AC | ACC_EX);
-        let tss_upper = &mut ENTRIES[ENTRY_COUNT - 1] as *mut _ as *mut u64;
-        *tss_upper = ((tss_ptr >> 32) & 0xFFFFFFFF) as u64;
-
-        POINTER.offset = ENTRIES.as_ptr() as usize;
-        POINTER.size = (ENTRY_COUNT * size_of::<Entry>() - 1) as u16;
-        load_gdt(&POINTER);
+        load(&mut ENTRIES, &TSS, &mut POINTER);
+    }
+}
+
+/// Loads a GDT for an application processor. Each CPU
+/// needs its own TSS (and thus a GDT to describe it),
+/// which is returned
+pub fn init_ap() -> &'static mut Tss {
+    let entries = Box::leak(Box::new(ENTRIES_INIT));
+    let tss = Box::leak(Box::new(TSS_INIT));
+    let pointer = Box::leak(Box::new(Pointer { offset: 0, size: 0 }));
+
+    unsafe {
+        load(entries, tss, pointer);
     }
+    tss
 }
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use crate::dev::irq;
+use crate::dev::x86::apic;
 use core::mem::size_of;
 
 #[repr(packed)]
@@ -59,6 +60,7 @@ static mut POINTER: Pointer = Pointer {
 extern "C" {
     static exception_vectors: [usize; 32];
     static irq_vectors: [usize; irq::MAX_VECTOR];
+    fn irq_resched();
 }
 global_asm!(include_str!("idt_s.S"));
 
@@ -77,10 +79,21 @@ pub fn init() {
         }
     }
 
+    unsafe {
+        ENTRIES[apic::RESCHED_VECTOR as usize] = Entry::new(irq_resched as usize,
+                                                            0x08, FLAG_PR | FLAG_INT32);
+    }
+
     unsafe {
         POINTER.offset = ENTRIES.as_ptr() as usize;
         POINTER.limit = (ENTRY_COUNT * size_of::<Entry>() - 1) as u16;
+    }
+    load();
+}
 
+/// Loads the IDT on the calling CPU, all CPUs share the table
+pub fn load() {
+    unsafe {
         llvm_asm!("lidt ($0)"::"{rdi}"(&POINTER):"memory");
     }
 }
@@ -3,3 +3,19 @@ pub fn halt() -> ! {
         unsafe { llvm_asm!("cli; hlt"); }
     }
 }
+
+/// Invalidates TLB entry for a single page
+#[inline(always)]
+pub fn flush_tlb_entry(virt: usize) {
+    unsafe { llvm_asm!("invlpg ($0)"::"r"(virt):"memory"); }
+}
+
+/// Returns (eax, ebx, ecx, edx) of CPUID `leaf`
+#[inline(always)]
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        llvm_asm!("cpuid":"={eax}"(eax),"={ebx}"(ebx),"={ecx}"(ecx),"={edx}"(edx):"{eax}"(leaf),"{ecx}"(0));
+    }
+    (eax, ebx, ecx, edx)
+}
@@ -2,6 +2,10 @@ pub mod gdt;
 pub mod idt;
 pub mod regs;
 pub mod context;
+pub mod cpu;
 pub mod exception;
 pub mod intrinsics;
 pub mod syscall;
+pub mod smp;
+pub mod tsc;
+pub mod usercopy;
@@ -0,0 +1,11 @@
+#[inline(always)]
+pub fn read() -> usize {
+    let mut val: usize;
+    unsafe { llvm_asm!("mov %cr4, $0":"=r"(val)) }
+    val
+}
+
+#[inline(always)]
+pub unsafe fn write(value: usize) {
+    llvm_asm!("mov $0, %cr4"::"r"(value):"memory");
+}
@@ -1,5 +1,11 @@
+pub mod cr0;
 pub mod cr2;
 pub mod cr3;
+pub mod cr4;
+
+pub const MSR_IA32_EFER: u32 = 0xC0000080;
+pub const MSR_IA32_GS_BASE: u32 = 0xC0000101;
+pub const MSR_IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
 
 pub unsafe fn rdmsr(r: u32) -> u64 {
     let mut res: u64;
@@ -0,0 +1,256 @@
+//! Application processor startup. An AP begins in real mode at
+//! the trampoline copied to low memory, which takes it to long
+//! mode with the kernel address space and calls `ap_main`
+
+use super::{cpu, gdt, idt};
+use crate::dev::x86::{apic, pit::delay_us};
+use crate::dev::x86::acpi::{self, MADT, MadtRecord};
+use crate::mem::{self, MapError, PageFlags, PAGE_SIZE};
+use crate::mem::phys::{self, PageUsage};
+use crate::{thread, time, virtualize};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Physical (and temporary virtual) address of the trampoline,
+/// it's below the kernel image so never handed out by the
+/// physical allocator
+const TRAMPOLINE: usize = 0x8000;
+const AP_STACK_PAGES: usize = 4;
+
+/// Filled in by the BSP before starting each AP, see
+/// `ap_trampoline_data`
+#[repr(C)]
+struct ApData {
+    cr3:    u64,
+    stack:  u64,
+    id:     u64,
+    entry:  u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+global_asm!(r#"
+.section .text
+.set ap_base, 0x8000
+
+.global ap_trampoline_start
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+
+    // PAE and global pages
+    mov %cr4, %eax
+    or $((1 << 5) | (1 << 7)), %eax
+    mov %eax, %cr4
+
+    // Kernel address space, has to be below 4GiB
+    movl ap_base + (ap_trampoline_data - ap_trampoline_start), %eax
+    mov %eax, %cr3
+
+    // Long mode and NX
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    lgdtl ap_base + (ap_gdt_pointer - ap_trampoline_start)
+
+    // Paging, ring 0 write protection and protected mode
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16) | 1), %eax
+    mov %eax, %cr0
+
+    ljmpl $0x08, $(ap_base + (ap_trampoline_64 - ap_trampoline_start))
+
+.code64
+ap_trampoline_64:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov $(ap_base + (ap_trampoline_data - ap_trampoline_start)), %rbx
+    mov 0x08(%rbx), %rsp
+    mov 0x10(%rbx), %rdi
+    // Leave identity-mapped code for the kernel
+    call *0x18(%rbx)
+1:
+    cli
+    hlt
+    jmp 1b
+
+.p2align 3
+ap_gdt:
+    .quad 0
+    .quad 0x00209A0000000000    // 64-bit code 0x08
+    .quad 0x0000920000000000    // data        0x10
+ap_gdt_pointer:
+    .word . - ap_gdt - 1
+    .long ap_base + (ap_gdt - ap_trampoline_start)
+
+.p2align 3
+ap_trampoline_data:
+    .quad 0     // cr3
+    .quad 0     // stack
+    .quad 0     // id
+    .quad 0     // entry
+ap_trampoline_end:
+"#);
+
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+static CPU_COUNT: AtomicU32 = AtomicU32::new(1);
+/// Local APIC IDs by CPU ID
+static APIC_IDS: [AtomicU32; thread::MAX_CPUS] = [AtomicU32::new(0); thread::MAX_CPUS];
+
+/// Number of CPUs started so far, including the BSP
+pub fn cpu_count() -> u32 {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Makes CPU `id` reschedule
+pub fn kick(id: usize) {
+    apic::send_fixed(APIC_IDS[id].load(Ordering::Relaxed), apic::RESCHED_VECTOR);
+}
+
+/// Local APIC ID of CPU `id`
+pub fn apic_id(id: u32) -> u32 {
+    APIC_IDS[id as usize].load(Ordering::Relaxed)
+}
+
+/// Records the BSP's local APIC ID, once it's initialized
+pub fn init_bsp() {
+    APIC_IDS[0].store(apic::id(), Ordering::Relaxed);
+}
+
+fn symbol(sym: &u8) -> usize {
+    sym as *const _ as usize
+}
+
+extern "C" fn ap_main(id: u64) -> ! {
+    let tss = gdt::init_ap();
+    cpu::init_ap(id as u32, tss);
+    idt::load();
+    mem::init();
+    super::syscall::init();
+    apic::init_ap();
+    acpi::init_local_nmi();
+    time::init_cpu();
+
+    println!("CPU {} is up", id);
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+    AP_STARTED.store(true, Ordering::Release);
+
+    unsafe {
+        thread::enter();
+    }
+}
+
+/// Waits for the AP to report in, `false` on timeout
+fn wait_started(ms: u32) -> bool {
+    for _ in 0 .. ms {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        delay_us(1000);
+    }
+    AP_STARTED.load(Ordering::Acquire)
+}
+
+/// Boots a single AP through INIT-SIPI-SIPI
+fn start_ap(data: &mut ApData, apic_id: u32, id: u32) -> bool {
+    let stack = match phys::alloc_contiguous(PageUsage::Kernel, AP_STACK_PAGES) {
+        Some(phys) => virtualize(phys),
+        None => return false
+    };
+    data.stack = (stack + AP_STACK_PAGES * PAGE_SIZE) as u64;
+    data.id = id as u64;
+    APIC_IDS[id as usize].store(apic_id, Ordering::Relaxed);
+    AP_STARTED.store(false, Ordering::Release);
+
+    apic::send_init(apic_id);
+    delay_us(10000);
+
+    // Second SIPI only if the first one got lost,
+    // a running CPU ignores it anyway
+    apic::send_startup(apic_id, (TRAMPOLINE >> 12) as u8);
+    if wait_started(1) {
+        return true;
+    }
+    apic::send_startup(apic_id, (TRAMPOLINE >> 12) as u8);
+    wait_started(1000)
+}
+
+/// Starts all the enabled processors listed in MADT. The
+/// lower half alias has to be removed by now
+pub fn init() {
+    let bsp_id = apic_id(0);
+    let apic_ids: Vec<u32> = match &*MADT.lock() {
+        Some(madt) => madt.iter().filter_map(|rec| match rec {
+            MadtRecord::LocalApic(_, apic_id, flags)
+                if flags & 1 != 0 && apic_id as u32 != bsp_id => Some(apic_id as u32),
+            // Only reachable without x2APIC mode if the ID fits
+            MadtRecord::LocalX2Apic(apic_id, flags, _)
+                if flags & 1 != 0 && apic_id != bsp_id && apic_id < 0xFF => Some(apic_id),
+            _ => None
+        }).collect(),
+        None => return
+    };
+    if apic_ids.is_empty() {
+        return;
+    }
+
+    let space = unsafe { mem::KERNEL.as_mut() }.unwrap();
+    let cr3 = space.physical();
+    assert!(cr3 < 0x100000000, "Kernel PML4 is not reachable from the trampoline");
+
+    // Code right after enabling paging runs at its physical address
+    let mapped = match space.map(TRAMPOLINE, TRAMPOLINE, PageFlags::WRITE) {
+        Ok(()) => true,
+        Err(MapError::AlreadyMapped) | Err(MapError::LargePage) => false,
+        Err(err) => {
+            println!("Failed to map AP trampoline: {:?}", err);
+            return;
+        }
+    };
+    let mut flags = PageFlags::empty();
+    let phys = mem::translate(space, TRAMPOLINE, Some(&mut flags));
+    assert!(phys == Some(TRAMPOLINE) && !flags.contains(PageFlags::NX),
+            "AP trampoline is not mapped executable");
+
+    let data = unsafe {
+        let start = symbol(&ap_trampoline_start);
+        let size = symbol(&ap_trampoline_end) - start;
+        core::ptr::copy_nonoverlapping(start as *const u8, virtualize(TRAMPOLINE) as *mut u8, size);
+        &mut *((virtualize(TRAMPOLINE) + symbol(&ap_trampoline_data) - start) as *mut ApData)
+    };
+    data.cr3 = cr3 as u64;
+    data.entry = ap_main as usize as u64;
+
+    for apic_id in apic_ids {
+        if cpu_count() as usize == thread::MAX_CPUS {
+            println!("Only {} CPUs are supported", thread::MAX_CPUS);
+            break;
+        }
+        if !start_ap(data, apic_id, cpu_count()) {
+            println!("CPU with APIC ID {} did not start", apic_id);
+        }
+    }
+    println!("{} CPUs online", cpu_count());
+
+    if mapped {
+        space.unmap(TRAMPOLINE).unwrap();
+    }
+}
@@ -1,82 +1,110 @@
-use crate::arch::x86::regs;
+use crate::arch::x86::regs::{self, MSR_IA32_EFER};
 
-const MSR_IA32_EFER: u32 = 0xC0000080;
 const MSR_IA32_STAR: u32 = 0xC0000081;
 const MSR_IA32_LSTAR: u32 = 0xC0000082;
 const MSR_IA32_SFMASK: u32 = 0xC0000084;
 
-extern "C" {
-    fn syscall_entry();
+/// User registers saved on syscall entry at the top of
+/// thread's kernel stack
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15:    usize,
+    pub r14:    usize,
+    pub r13:    usize,
+    pub r12:    usize,
+    pub rbp:    usize,
+    pub rbx:    usize,
+
+    // Arguments, in reverse order
+    pub r9:     usize,
+    pub r8:     usize,
+    pub r10:    usize,
+    pub rdx:    usize,
+    pub rsi:    usize,
+    pub rdi:    usize,
+    /// System call number
+    pub rax:    usize,
+
+    pub rip:    usize,
+    pub rflags: usize,
+    pub rsp:    usize,
 }
 
-#[no_mangle]
-extern "C" fn syscall_undefined(no: usize) {
-    panic!("Undefined system call: {}", no);
+impl SyscallFrame {
+    pub fn args(&self) -> [usize; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+extern "C" {
+    fn syscall_entry();
 }
 
 global_asm!(r#"
 .section .text
 .global syscall_entry
 syscall_entry:
-    // TODO: swapgs n shiet
     // rip -> rcx
     // rflags -> r11
+    swapgs
 
-    // Store user stack in temporary location
-    mov %rsp, syscall_stack(%rip)
-    // Switch to kernel stack
-    mov CURRENT(%rip), %rsp // TODO: null check? Don't think this can happen, but still
-    mov 0x00(%rsp), %rsp
+    // Store user stack in per-CPU scratch slot and
+    // switch to the top of kernel stack
+    mov %rsp, %gs:0x18
+    mov %gs:0x10, %rsp
 
-    // Can do stuff with stack now
-    push %rcx
+    // Can do stuff with stack now, build SyscallFrame
+    pushq %gs:0x18
     push %r11
-    mov syscall_stack(%rip), %rcx
     push %rcx
-
-    cmp $256, %rax
-    jge 1f
-    lea SYSCALL_TABLE(%rip), %rcx
-    mov (%rcx, %rax, 8), %rcx
-    test %rcx, %rcx
-    jz 1f
-
-    // Fixup ABI/argument order
-    mov %rcx, %rax
-    mov %r10, %rcx
+    push %rax
+    push %rdi
+    push %rsi
+    push %rdx
+    push %r10
+    push %r8
+    push %r9
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
 
     // TODO: interrupts/TSS here?
-    call *%rax
-
-    jmp 2f
-1:
-    mov %rax, %rdi
-    call syscall_undefined
-2:
-
+    mov %rsp, %rdi
+    call syscall_dispatch
+
+    // Result is in %rax, everything else except
+    // %rcx and %r11 is preserved
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    pop %r9
+    pop %r8
+    pop %r10
+    pop %rdx
+    pop %rsi
     pop %rdi
-    pop %r11
+    add $8, %rsp
     pop %rcx
+    pop %r11
+    pop %rsp
 
-    mov CURRENT(%rip), %rsp
-    mov %rdi, 0x00(%rsp)
-    mov %rdi, %rsp
-
-    // TODO: swapgs back
-
+    swapgs
     sysretq
-
-.section .data
-syscall_stack:
-    .quad 0
 "#);
 
 pub fn init() {
     unsafe {
         // Syscall entry
         regs::wrmsr(MSR_IA32_LSTAR, syscall_entry as usize as u64);
-        // Mask out IF each time syscall is executed
-        regs::wrmsr(MSR_IA32_SFMASK, 1 << 9);
+        // Mask out IF, DF and AC each time syscall is executed:
+        // user copies (rep movsb) rely on DF being clear
+        regs::wrmsr(MSR_IA32_SFMASK, (1 << 9) | (1 << 10) | (1 << 18));
         // Segment registers to use when switching to syscall context
         regs::wrmsr(MSR_IA32_STAR, ((0x1Bu64 - 8) << 48) | (0x08u64 << 32));
 
@@ -0,0 +1,52 @@
+//! Time stamp counter as a clock source
+
+use crate::arch::x86::intrinsics;
+use crate::time::ClockSource;
+
+pub struct Tsc {
+    frequency: u64,
+}
+
+static mut TSC: Tsc = Tsc { frequency: 0 };
+
+#[inline(always)]
+pub fn read() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { llvm_asm!("rdtsc":"={eax}"(lo),"={edx}"(hi)); }
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// Whether the TSC runs at a constant rate regardless of
+/// power states
+pub fn is_invariant() -> bool {
+    let (max, _, _, _) = intrinsics::cpuid(0x80000000);
+    max >= 0x80000007 && intrinsics::cpuid(0x80000007).3 & (1 << 8) != 0
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+/// Measures TSC frequency, `delay` has to busy-wait
+/// for the given number of microseconds
+pub fn calibrate(delay: fn(u32)) -> &'static Tsc {
+    let start = read();
+    delay(10000);
+    let end = read();
+
+    unsafe {
+        TSC.frequency = (end - start) * 100;
+        println!("TSC: {} MHz", TSC.frequency / 1000000);
+        &TSC
+    }
+}
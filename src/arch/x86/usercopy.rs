@@ -0,0 +1,88 @@
+//! Primitives for accessing user memory which may fault.
+//! Faulting instructions are covered by exception fixups
+
+use super::exception::add_fixup;
+
+extern "C" {
+    fn copy_user_raw(dst: usize, src: usize, len: usize) -> usize;
+    fn strncpy_user_raw(dst: usize, src: usize, max: usize) -> isize;
+
+    static copy_user_start: u8;
+    static copy_user_end: u8;
+    static copy_user_fault: u8;
+    static strncpy_user_start: u8;
+    static strncpy_user_end: u8;
+    static strncpy_user_fault: u8;
+}
+
+global_asm!(r#"
+.section .text
+// %rdi - destination, %rsi - source, %rdx - byte count
+// Returns number of bytes not copied
+.global copy_user_raw
+copy_user_raw:
+    mov %rdx, %rcx
+.global copy_user_start
+copy_user_start:
+    rep movsb
+.global copy_user_end
+copy_user_end:
+    xor %rax, %rax
+    ret
+.global copy_user_fault
+copy_user_fault:
+    // %rcx has the remaining count
+    mov %rcx, %rax
+    ret
+
+// %rdi - destination, %rsi - source, %rdx - max length
+// Returns string length, max if it's not terminated
+// within max bytes or -1 on fault
+.global strncpy_user_raw
+strncpy_user_raw:
+    xor %rax, %rax
+1:
+    cmp %rdx, %rax
+    je 2f
+.global strncpy_user_start
+strncpy_user_start:
+    movb (%rsi, %rax), %cl
+.global strncpy_user_end
+strncpy_user_end:
+    movb %cl, (%rdi, %rax)
+    test %cl, %cl
+    jz 2f
+    inc %rax
+    jmp 1b
+2:
+    ret
+.global strncpy_user_fault
+strncpy_user_fault:
+    mov $-1, %rax
+    ret
+"#);
+
+/// Copies `len` bytes, either side may be user memory.
+/// Returns number of bytes left uncopied due to a fault
+pub unsafe fn copy_user(dst: usize, src: usize, len: usize) -> usize {
+    copy_user_raw(dst, src, len)
+}
+
+/// Copies a NUL-terminated string from user `src`. Returns
+/// its length (`max` if unterminated) or `None` on fault
+pub unsafe fn strncpy_user(dst: usize, src: usize, max: usize) -> Option<usize> {
+    let len = strncpy_user_raw(dst, src, max);
+    if len < 0 {
+        None
+    } else {
+        Some(len as usize)
+    }
+}
+
+pub fn init() {
+    let addr = |sym: &u8| sym as *const _ as usize;
+    unsafe {
+        add_fixup(addr(&copy_user_start), addr(&copy_user_end), addr(&copy_user_fault));
+        add_fixup(addr(&strncpy_user_start), addr(&strncpy_user_end), addr(&strncpy_user_fault));
+    }
+}
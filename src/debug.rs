@@ -1,5 +1,6 @@
 use crate::dev::{x86::COM1, SerialDevice};
 use crate::sync::IrqDisable;
+use crate::time::{self, DateTime, NSEC_PER_SEC, NSEC_PER_MSEC};
 use core::fmt;
 
 pub enum Level {
@@ -35,6 +36,7 @@ fn write_fmt_raw(args: fmt::Arguments) -> fmt::Result {
 pub fn write_fmt(level: Level, file: &str, line: u32, args: fmt::Arguments) -> fmt::Result {
     use core::fmt::Write;
     let _lock = IrqDisable::new();
+    let now = time::try_realtime();
     let mut wr = SerialWriter {
         port: &mut *COM1.lock()
     };
@@ -44,6 +46,11 @@ pub fn write_fmt(level: Level, file: &str, line: u32, args: fmt::Arguments) -> f
         Level::Fatal    => wr.write_str("\x1b[41;1m")?,
         _               => (),
     }
+    if let Some(now) = now {
+        wr.write_fmt(format_args!("[{}.{:03}] ",
+                                  DateTime::from_unix(now / NSEC_PER_SEC),
+                                  now % NSEC_PER_SEC / NSEC_PER_MSEC))?;
+    }
     wr.write_fmt(format_args!("[{}:{}] ", file, line))?;
     wr.write_fmt(args)?;
     // TODO: only when necessary
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
 pub trait IrqHandler {
     fn handle(&mut self) -> bool;
 }
@@ -57,8 +59,41 @@ type VectorNumber = usize;
 
 pub const MAX_VECTOR: VectorNumber = 32;
 pub const MAX_SLOT: SlotNumber = 4;
+/// IDT vector of IRQ vector 0, the rest follow it
+pub const IDT_BASE: u32 = 32;
 
 static mut IRQ: [IrqVector; MAX_VECTOR] = [IrqVector::empty(); MAX_VECTOR];
+/// Bitmap of vectors taken by drivers, 0 is the timer
+static USED: AtomicU32 = AtomicU32::new(1);
+
+pub fn idt_vector(vec: VectorNumber) -> u32 {
+    IDT_BASE + vec as u32
+}
+
+/// Takes a free vector
+pub fn alloc_vector() -> Option<VectorNumber> {
+    let mut used = USED.load(Ordering::Relaxed);
+    loop {
+        if used == !0 {
+            return None;
+        }
+        let vec = (!used).trailing_zeros();
+        match USED.compare_exchange_weak(used, used | (1 << vec), Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return Some(vec as VectorNumber),
+            Err(current) => used = current
+        }
+    }
+}
+
+/// Takes a vector hardwired to some device, `false`
+/// if it's already taken
+pub fn reserve_vector(vec: VectorNumber) -> bool {
+    USED.fetch_or(1 << vec, Ordering::AcqRel) & (1 << vec) == 0
+}
+
+pub fn free_vector(vec: VectorNumber) {
+    USED.fetch_and(!(1 << vec), Ordering::AcqRel);
+}
 
 pub fn add(vec: VectorNumber, h: &'static mut dyn IrqHandler) {
     let bind = IrqHandlerBind::new(h);
@@ -67,8 +102,15 @@ pub fn add(vec: VectorNumber, h: &'static mut dyn IrqHandler) {
 
 #[no_mangle]
 extern "C" fn do_irq_0() {
-    use crate::thread;
-    unsafe { thread::r#yield(); }
+    use crate::{thread, time};
+    time::tick();
+    unsafe { thread::tick(); }
+}
+
+/// Another CPU has queued a thread here, no tick is charged
+#[no_mangle]
+extern "C" fn do_resched() {
+    unsafe { crate::thread::preempt(); }
 }
 
 #[no_mangle]
@@ -13,11 +13,29 @@ pub struct Header {
     pub creator_rev:    u32
 }
 
-pub trait Table {
+/// Generic Address Structure
+#[repr(packed)]
+pub struct GenericAddress {
+    /// 0 - memory, 1 - I/O ports
+    pub space_id:       u8,
+    pub bit_width:      u8,
+    pub bit_offset:     u8,
+    pub access_size:    u8,
+    pub address:        u64
+}
+
+/// Bytes of a valid structure add up to zero
+pub fn checksum(base: usize, length: usize) -> bool {
+    (0 .. length).fold(0u8, |sum, i| sum.wrapping_add(unsafe { *((base + i) as *const u8) })) == 0
+}
+
+pub trait Table: Sized {
     const SIGNATURE: [u8; 4];
 
+    /// Every table starts with a `Header`
     fn is_valid(&self) -> bool {
-        true
+        let hdr = unsafe { &*(self as *const Self as *const Header) };
+        hdr.signature == Self::SIGNATURE && hdr.is_checksum_valid()
     }
 }
 
@@ -27,10 +45,21 @@ impl Header {
         self.length as usize - size_of::<Header>()
     }
 
+    pub fn is_checksum_valid(&self) -> bool {
+        checksum(self as *const _ as usize, self.length as usize)
+    }
+
     pub fn table<T: Table>(&mut self) -> Option<&'static mut T> {
         if T::SIGNATURE == self.signature {
             let self_addr = self as *mut _ as usize;
-            Some(unsafe { &mut *(self_addr as *mut _) })
+            let table: &'static mut T = unsafe { &mut *(self_addr as *mut _) };
+            if table.is_valid() {
+                Some(table)
+            } else {
+                println!("ACPI table {} is invalid",
+                         core::str::from_utf8(&T::SIGNATURE).unwrap_or("????"));
+                None
+            }
         } else {
             None
         }
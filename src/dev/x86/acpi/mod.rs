@@ -11,40 +11,174 @@ use spin::Mutex;
 
 pub static FADT: Mutex<Option<&'static mut Fadt>> = Mutex::new(None);
 pub static MADT: Mutex<Option<&'static mut Madt>> = Mutex::new(None);
+pub static HPET: Mutex<Option<&'static mut Hpet>> = Mutex::new(None);
 
-fn init_rsdp(addr: usize) {
-    let rsdp = unsafe { &*(addr as *const RootPointer) };
-    if !rsdp.is_valid() {
-        panic!("ACPI root pointer is invalid");
+fn add_table(item: &'static mut Header) {
+    // TODO: rewrite to match?
+    if let Some(table) = item.table::<Fadt>() {
+        *FADT.lock() = Some(table);
+    }
+    if let Some(table) = item.table::<Madt>() {
+        *MADT.lock() = Some(table);
+    }
+    if let Some(table) = item.table::<Hpet>() {
+        *HPET.lock() = Some(table);
+    }
+}
+
+/// Searches the first KiB of EBDA, then the BIOS area
+fn find_rsdp() -> Option<usize> {
+    let ebda = (unsafe { *(virtualize(0x40E) as *const u16) } as usize) << 4;
+    if ebda >= 0x80000 && ebda < 0xA0000 {
+        if let Some(addr) = RootPointer::search(ebda, ebda + 1024) {
+            return Some(addr);
+        }
+    }
+    RootPointer::search(0xE0000, 0x100000)
+}
+
+fn init_rsdp(addr: usize) -> bool {
+    let rsdp = unsafe { &*(virtualize(addr) as *const RootPointer) };
+
+    // XSDT supersedes RSDT when both are present
+    if let Some(addr) = rsdp.xsdt() {
+        let xsdt = unsafe { &*(virtualize(addr) as *const Xsdt) };
+        if xsdt.is_valid() {
+            xsdt.iter().for_each(add_table);
+            return true;
+        }
+        println!("XSDT is invalid, falling back to RSDT");
     }
 
     let rsdt = unsafe { &*(virtualize(rsdp.rsdt_address as usize) as *const Rsdt) };
-    assert!(rsdt.is_valid());
+    if !rsdt.is_valid() {
+        println!("RSDT is invalid");
+        return false;
+    }
+    rsdt.iter().for_each(add_table);
+    true
+}
+
+/// Physical address of local APICs
+pub fn local_apic_address() -> usize {
+    let mut address = 0xFEE00000;
+    if let Some(madt) = &*MADT.lock() {
+        address = madt.local_apic as usize;
+        for rec in madt.iter() {
+            if let MadtRecord::LocalApicAddress(addr) = rec {
+                address = addr as usize;
+            }
+        }
+    }
+    address
+}
+
+/// Sets up the NMI inputs of the calling CPU's local APIC
+pub fn init_local_nmi() {
+    use crate::dev::x86::{apic, ioapic::Polarity};
+
+    let madt = MADT.lock();
+    let madt = match &*madt {
+        Some(madt) => madt,
+        None => return
+    };
 
-    for item in rsdt.iter() {
-        // TODO: rewrite to match?
-        if let Some(table) = item.table::<Fadt>() {
-            *FADT.lock() = Some(table);
+    let apic_id = apic::id();
+    let mut uid = None;
+    for rec in madt.iter() {
+        match rec {
+            MadtRecord::LocalApic(acpi_id, id, _) if id as u32 == apic_id => {
+                uid = Some(acpi_id as u32);
+            },
+            MadtRecord::LocalX2Apic(id, _, acpi_uid) if id == apic_id => {
+                uid = Some(acpi_uid);
+            },
+            _ => ()
         }
-        if let Some(table) = item.table::<Madt>() {
-            *MADT.lock() = Some(table);
+    }
+
+    for rec in madt.iter() {
+        let (target, flags, lint) = match rec {
+            MadtRecord::LocalApicNmi(0xFF, flags, lint) => (None, flags, lint),
+            MadtRecord::LocalApicNmi(acpi_id, flags, lint) => (Some(acpi_id as u32), flags, lint),
+            MadtRecord::LocalX2ApicNmi(0xFFFFFFFF, flags, lint) => (None, flags, lint),
+            MadtRecord::LocalX2ApicNmi(acpi_uid, flags, lint) => (Some(acpi_uid), flags, lint),
+            _ => continue
+        };
+        if lint <= 1 && (target.is_none() || target == uid) {
+            apic::set_lint_nmi(lint, Polarity::from_inti(flags));
         }
     }
 }
 
-pub fn init(from_loader: Option<usize>) {
-    // TODO: RSDP location if not provided
-    let rsdp_address = from_loader.unwrap();
-    init_rsdp(rsdp_address);
+/// Routes I/O APIC NMI sources to the BSP, which has to be
+/// known to `smp` by now
+pub fn init_nmi_sources() {
+    use crate::dev::x86::ioapic::{self, Polarity, Trigger};
+
+    if let Some(madt) = &*MADT.lock() {
+        for rec in madt.iter() {
+            if let MadtRecord::NmiSource(flags, gsi) = rec {
+                if let Err(err) = ioapic::set_nmi(gsi, Polarity::from_inti(flags),
+                                                  Trigger::from_inti(flags)) {
+                    println!("NMI source GSI {}: {:?}", gsi, err);
+                }
+            }
+        }
+    }
+}
+
+/// Returns `false` if there's no ACPI
+pub fn init(from_loader: Option<usize>) -> bool {
+    let from_loader = from_loader.filter(|&addr| {
+        let valid = unsafe { &*(virtualize(addr) as *const RootPointer) }.is_valid();
+        if !valid {
+            println!("ACPI root pointer from the loader is invalid");
+        }
+        valid
+    });
+    let rsdp_address = match from_loader.or_else(find_rsdp) {
+        Some(addr) => addr,
+        None => {
+            println!("ACPI root pointer not found");
+            return false;
+        }
+    };
+    if !init_rsdp(rsdp_address) {
+        return false;
+    }
 
     // Iterate MADT to find I/O APIC record
     if let Some(madt) = &*MADT.lock() {
-        use crate::dev::x86::ioapic;
+        use crate::dev::x86::ioapic::{self, Polarity, Trigger};
+
+        for rec in madt.iter() {
+            if let MadtRecord::IoApic(_, addr, gsi_base) = rec {
+                ioapic::init(virtualize(addr as usize), gsi_base);
+            }
+        }
 
+        // Then what's connected to it
         for rec in madt.iter() {
-            if let MadtRecord::IoApic(_, addr, _) = rec {
-                ioapic::init(virtualize(addr as usize));
+            if let MadtRecord::InterruptOverride(0, irq, gsi, flags) = rec {
+                if irq < 16 {
+                    ioapic::set_isa_override(irq, gsi,
+                                             Polarity::from_inti(flags),
+                                             Trigger::from_inti(flags));
+                }
             }
         }
     }
+
+    if let Some(table) = &*HPET.lock() {
+        use crate::dev::x86::hpet;
+
+        if table.address.space_id == 0 {
+            hpet::init(virtualize(table.address.address as usize));
+        } else {
+            println!("HPET is not memory-mapped");
+        }
+    }
+
+    true
 }
@@ -1,9 +1,11 @@
 use core::marker::PhantomData;
 use core::mem::size_of;
+use core::ptr::read_unaligned;
 
-use super::base::{Header, Table};
+use super::base::{checksum, Header, Table};
+use crate::virtualize;
 
-// RSDP + RSDT
+// RSDP + RSDT/XSDT
 
 #[repr(packed)]
 pub struct RootPointer {
@@ -11,9 +13,17 @@ pub struct RootPointer {
     pub checksum:       u8,
     pub oem_id:         [u8; 6],
     pub revision:       u8,
-    pub rsdt_address:   u32
+    pub rsdt_address:   u32,
+    // ACPI 2.0+ (revision >= 2)
+    pub length:         u32,
+    pub xsdt_address:   u64,
+    pub ext_checksum:   u8,
+    _res0:              [u8; 3]
 }
 
+/// Size of the ACPI 1.0 part covered by `checksum`
+const RSDP_V1_SIZE: usize = 20;
+
 #[repr(packed)]
 pub struct RootSdt<T: Sized> {
     hdr:    Header,
@@ -22,11 +32,16 @@ pub struct RootSdt<T: Sized> {
 }
 
 pub type Rsdt = RootSdt<u32>;
+pub type Xsdt = RootSdt<u64>;
 
 impl Table for Rsdt {
     const SIGNATURE: [u8; 4] = *b"RSDT";
 }
 
+impl Table for Xsdt {
+    const SIGNATURE: [u8; 4] = *b"XSDT";
+}
+
 pub struct SdtIterator<T: Sized> {
     base:   usize,
     index:  usize,
@@ -34,7 +49,7 @@ pub struct SdtIterator<T: Sized> {
     _0:     PhantomData<T>
 }
 
-impl Iterator for SdtIterator<u32> {
+impl<T: Copy + Into<u64>> Iterator for SdtIterator<T> {
     type Item = &'static mut Header;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -42,17 +57,38 @@ impl Iterator for SdtIterator<u32> {
             return None;
         }
 
-        let ptr = unsafe { *((self.base + self.index * 4) as *mut u32) };
+        // XSDT entries aren't 8-byte aligned
+        let ptr: T = unsafe { read_unaligned((self.base + self.index * size_of::<T>()) as *const T) };
         self.index += 1;
-        Some(unsafe { &mut *(ptr as *mut _) })
+        Some(unsafe { &mut *(virtualize(ptr.into() as usize) as *mut _) })
     }
 }
 
 impl RootPointer {
     pub fn is_valid(&self) -> bool {
-        self.signature == [b'R', b'S', b'D', b' ',
-                           b'P', b'T', b'R', b' ']
-        // TODO: checksum
+        let base = self as *const _ as usize;
+        if self.signature != [b'R', b'S', b'D', b' ',
+                              b'P', b'T', b'R', b' '] || !checksum(base, RSDP_V1_SIZE) {
+            return false;
+        }
+        self.revision < 2 || checksum(base, self.length as usize)
+    }
+
+    /// Looks for a valid root pointer on 16-byte
+    /// boundaries of a physical memory range
+    pub fn search(start: usize, end: usize) -> Option<usize> {
+        (start .. end).step_by(16).find(|&addr| {
+            unsafe { &*(virtualize(addr) as *const RootPointer) }.is_valid()
+        })
+    }
+
+    /// XSDT address, if the pointer has one
+    pub fn xsdt(&self) -> Option<usize> {
+        if self.revision >= 2 && self.xsdt_address != 0 {
+            Some(self.xsdt_address as usize)
+        } else {
+            None
+        }
     }
 }
 
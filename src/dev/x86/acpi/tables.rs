@@ -1,4 +1,4 @@
-use super::base::{Table, Header};
+use super::base::{Table, Header, GenericAddress};
 use core::mem::size_of;
 
 #[repr(packed)]
@@ -7,6 +7,30 @@ pub struct Fadt {
     pub firmware_ctrl:  u32,
     pub dsdt:           u32,
     _res0:              u8,
+    pub pm_profile:     u8,
+    pub sci_int:        u16,
+    pub smi_cmd:        u32,
+    pub acpi_enable:    u8,
+    pub acpi_disable:   u8,
+    pub s4bios_req:     u8,
+    pub pstate_cnt:     u8,
+    /// PM1a/b event, PM1a/b control, PM2 control, PM timer,
+    /// GPE0 and GPE1 blocks
+    pub blocks:         [u32; 8],
+    /// PM1 event, PM1 control, PM2 control, PM timer, GPE0
+    /// and GPE1 block lengths, GPE1 base, C state control
+    pub block_lens:     [u8; 8],
+    pub p_lvl2_lat:     u16,
+    pub p_lvl3_lat:     u16,
+    pub flush_size:     u16,
+    pub flush_stride:   u16,
+    pub duty_offset:    u8,
+    pub duty_width:     u8,
+    /// RTC CMOS indices of the day/month alarms and the
+    /// century, 0 if not supported
+    pub day_alarm:      u8,
+    pub month_alarm:    u8,
+    pub century:        u8,
     // ...
 }
 
@@ -31,8 +55,22 @@ pub struct MadtIterator {
 }
 
 pub enum MadtRecord {
+    /// Processor ID, APIC ID, flags
     LocalApic(u8, u8, u32),
+    /// I/O APIC ID, address, GSI base
     IoApic(u8, u32, u32),
+    /// Bus, ISA IRQ, GSI, INTI flags
+    InterruptOverride(u8, u8, u32, u16),
+    /// INTI flags, GSI
+    NmiSource(u16, u32),
+    /// Processor ID (0xFF - all), INTI flags, LINT#
+    LocalApicNmi(u8, u16, u8),
+    /// 64-bit local APIC address
+    LocalApicAddress(u64),
+    /// x2APIC ID, flags, processor UID
+    LocalX2Apic(u32, u32, u32),
+    /// Processor UID (0xFFFFFFFF - all), INTI flags, LINT#
+    LocalX2ApicNmi(u32, u16, u8),
     Unknown(u8, u8)
 }
 
@@ -62,13 +100,25 @@ impl Iterator for MadtIterator {
         let (kind, len) = unsafe {
             (*ptr, *ptr.offset(1))
         };
+        if len < 2 {
+            return None;
+        }
+
+        let u16_at = |off| unsafe { (ptr.offset(off) as *const u16).read_unaligned() };
+        let u32_at = |off| unsafe { (ptr.offset(off) as *const u32).read_unaligned() };
+        let u8_at = |off| unsafe { *ptr.offset(off) };
 
         let res = match kind {
-            1   => Some(unsafe {
-                MadtRecord::IoApic(*ptr.offset(2),
-                                   *(ptr.offset(4) as *const _),
-                                   *(ptr.offset(8) as *const _))
-            }),
+            0   => Some(MadtRecord::LocalApic(u8_at(2), u8_at(3), u32_at(4))),
+            1   => Some(MadtRecord::IoApic(u8_at(2), u32_at(4), u32_at(8))),
+            2   => Some(MadtRecord::InterruptOverride(u8_at(2), u8_at(3), u32_at(4), u16_at(8))),
+            3   => Some(MadtRecord::NmiSource(u16_at(2), u32_at(4))),
+            4   => Some(MadtRecord::LocalApicNmi(u8_at(2), u16_at(3), u8_at(5))),
+            5   => Some(MadtRecord::LocalApicAddress(unsafe {
+                (ptr.offset(4) as *const u64).read_unaligned()
+            })),
+            9   => Some(MadtRecord::LocalX2Apic(u32_at(4), u32_at(8), u32_at(12))),
+            10  => Some(MadtRecord::LocalX2ApicNmi(u32_at(4), u16_at(2), u8_at(8))),
             _   => Some(MadtRecord::Unknown(kind, len))
         };
 
@@ -77,3 +127,20 @@ impl Iterator for MadtIterator {
         return res;
     }
 }
+
+////
+
+#[repr(packed)]
+pub struct Hpet {
+    pub hdr:            Header,
+    pub block_id:       u32,
+    pub address:        GenericAddress,
+    pub number:         u8,
+    /// Minimum periodic mode tick, in counter ticks
+    pub min_tick:       u16,
+    pub protection:     u8
+}
+
+impl Table for Hpet {
+    const SIGNATURE: [u8; 4] = *b"HPET";
+}
@@ -1,18 +1,40 @@
 use core::ptr::{write_volatile, read_volatile, null_mut};
+use crate::sync::IrqDisable;
+use crate::dev::x86::ioapic::Polarity;
+use core::cmp::{min, max};
 use spin::Mutex;
 
 pub struct LocalApic {
-    address: usize
+    address: usize,
+    /// Timer frequency with the divider used
+    ticks_per_ms: u32,
 }
 
+/// IDT vector of the local APIC timer
+pub const TIMER_VECTOR: u32 = 32;
+/// IDT vector of rescheduling IPIs, above all IRQ vectors
+pub const RESCHED_VECTOR: u32 = 0xF0;
+
+const LVT_MASKED: u32       = 1 << 16;
+const LVTT_PERIODIC: u32    = 1 << 17;
+const LVT_EXTINT: u32       = 7 << 8;
+const LVT_NMI: u32          = 4 << 8;
+const LVT_ACTIVE_LOW: u32   = 1 << 13;
+/// Divide by 16
+const TIMER_DIVIDER: u32    = 0x3;
+
 #[repr(usize)]
 pub enum Reg {
     ID = 0x20,
     TPR = 0x80,
     EOI = 0xB0,
     SVR = 0xF0,
+    ICR_LO = 0x300,
+    ICR_HI = 0x310,
 
     LVTT = 0x320,
+    LVT0 = 0x350,
+    LVT1 = 0x360,
 
     TMRINITCNT = 0x380,
     TMRCURRCNT = 0x390,
@@ -30,29 +52,131 @@ impl LocalApic {
         unsafe { read_volatile((self.address + reg as usize) as *mut u32) }
     }
 
+    /// Sends an inter-processor interrupt, `command` goes to
+    /// the lower half of ICR
+    fn send_ipi(&mut self, apic_id: u32, command: u32) {
+        self.write(Reg::ICR_HI, apic_id << 24);
+        self.write(Reg::ICR_LO, command);
+
+        // Wait for delivery
+        while self.read(Reg::ICR_LO) & (1 << 12) != 0 {
+            unsafe { llvm_asm!("pause"); }
+        }
+    }
+
     fn init(&mut self) {
         let tmp = self.read(Reg::SVR);
         self.write(Reg::SVR, tmp | (1 << 8) | 0xFF);
 
-        // Setup APIC timer
-        self.write(Reg::TMRDIV, 0x3);
-        self.write(Reg::LVTT, 32 | (1 << 17));
-        self.write(Reg::TMRINITCNT, 150000);
-        self.write(Reg::TMRCURRCNT, 0);
+        // Timer stays off until `time` sets it up
+        self.write(Reg::TMRDIV, TIMER_DIVIDER);
+        self.write(Reg::LVTT, TIMER_VECTOR | LVT_MASKED);
+        self.write(Reg::TMRINITCNT, 0);
+    }
+
+    /// Measures timer frequency, `delay` has to busy-wait
+    /// for the given number of microseconds
+    fn calibrate(&mut self, delay: fn(u32)) {
+        self.write(Reg::TMRDIV, TIMER_DIVIDER);
+        self.write(Reg::LVTT, TIMER_VECTOR | LVT_MASKED);
+        self.write(Reg::TMRINITCNT, 0xFFFFFFFF);
+        delay(10000);
+        let elapsed = 0xFFFFFFFF - self.read(Reg::TMRCURRCNT);
+        self.write(Reg::TMRINITCNT, 0);
+
+        self.ticks_per_ms = max(elapsed / 10, 1);
+    }
+
+    fn timer_periodic(&mut self, hz: u32) {
+        self.write(Reg::LVTT, TIMER_VECTOR | LVTT_PERIODIC);
+        self.write(Reg::TMRINITCNT, max(self.ticks_per_ms * 1000 / hz, 1));
+    }
+
+    fn timer_oneshot(&mut self, ns: u64) {
+        let count = ns * self.ticks_per_ms as u64 / 1000000;
+        self.write(Reg::LVTT, TIMER_VECTOR);
+        self.write(Reg::TMRINITCNT, max(min(count, 0xFFFFFFFF), 1) as u32);
     }
 }
 
 #[no_mangle]
 static mut apic_eoi: *mut u32 = null_mut();
-static APIC: Mutex<LocalApic> = Mutex::new(LocalApic { address: 0 });
+static APIC: Mutex<LocalApic> = Mutex::new(LocalApic { address: 0, ticks_per_ms: 0 });
 
 pub fn init(address: usize) {
     // TODO: check if already?
     println!("APIC base is 0x{:016x}", address);
 
     *APIC.lock() = LocalApic {
-        address: address
+        address: address,
+        ticks_per_ms: 0,
     };
     unsafe { apic_eoi = (address + Reg::EOI as usize) as *mut _; }
     APIC.lock().init();
 }
+
+/// Sets up local APIC of an application processor, the
+/// registers are at the same address on every CPU
+pub fn init_ap() {
+    APIC.lock().init();
+}
+
+/// Local APIC ID of the calling CPU
+pub fn id() -> u32 {
+    APIC.lock().read(Reg::ID) >> 24
+}
+
+pub fn send_init(apic_id: u32) {
+    // INIT, level assert
+    APIC.lock().send_ipi(apic_id, (5 << 8) | (1 << 14));
+}
+
+/// Makes the CPU start executing real-mode code at
+/// `vector * 0x1000`
+pub fn send_startup(apic_id: u32, vector: u8) {
+    APIC.lock().send_ipi(apic_id, (6 << 8) | (1 << 14) | vector as u32);
+}
+
+/// Sends `vector` to another CPU (or this one)
+pub fn send_fixed(apic_id: u32, vector: u32) {
+    let _irq = IrqDisable::new();
+    APIC.lock().send_ipi(apic_id, (1 << 14) | vector);
+}
+
+/// Passes 8259 PIC interrupts through LINT0 of this CPU
+pub fn enable_extint() {
+    let _irq = IrqDisable::new();
+    APIC.lock().write(Reg::LVT0, LVT_EXTINT);
+}
+
+/// Makes LINT`lint` of this CPU an NMI input, NMIs
+/// are always edge-triggered
+pub fn set_lint_nmi(lint: u8, polarity: Polarity) {
+    let mut value = LVT_NMI;
+    if polarity == Polarity::Low {
+        value |= LVT_ACTIVE_LOW;
+    }
+    let _irq = IrqDisable::new();
+    APIC.lock().write(if lint == 0 { Reg::LVT0 } else { Reg::LVT1 }, value);
+}
+
+/// Calibrates the timer of this CPU, other CPUs' timers
+/// are assumed to run at the same rate
+pub fn calibrate_timer(delay: fn(u32)) {
+    let _irq = IrqDisable::new();
+    let mut apic = APIC.lock();
+    apic.calibrate(delay);
+    println!("APIC timer: {} kHz", apic.ticks_per_ms);
+}
+
+/// Interrupts this CPU at `hz` rate
+pub fn timer_periodic(hz: u32) {
+    let _irq = IrqDisable::new();
+    APIC.lock().timer_periodic(hz);
+}
+
+/// Interrupts this CPU once, after `ns` nanoseconds
+pub fn timer_oneshot(ns: u64) {
+    let _irq = IrqDisable::new();
+    APIC.lock().timer_oneshot(ns);
+}
@@ -0,0 +1,218 @@
+//! High Precision Event Timer. The main counter serves as a
+//! clock source and a calibration reference, comparators can
+//! raise interrupts through the I/O APIC
+
+use crate::dev::irq::{self, IrqHandler};
+use crate::dev::x86::ioapic::{self, Polarity, Trigger};
+use crate::time::{ClockSource, NSEC_PER_SEC};
+use core::ptr::{read_volatile, write_volatile};
+
+const REG_CAPS: usize         = 0x00;
+const REG_CONFIG: usize       = 0x10;
+const REG_COUNTER: usize      = 0xF0;
+
+const CAPS_COUNT_64: u64      = 1 << 13;
+const CONFIG_ENABLE: u64      = 1 << 0;
+const CONFIG_LEGACY: u64      = 1 << 1;
+
+const TIMER_INT_ENABLE: u64   = 1 << 2;
+const TIMER_PERIODIC: u64     = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64    = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64  = 9;
+const TIMER_ROUTE_MASK: u64   = 0x1F << TIMER_ROUTE_SHIFT;
+
+/// Counter period can't be longer than 100ns
+const MAX_PERIOD_FS: u64 = 100000000;
+const FSEC_PER_SEC: u64 = 1000000000000000;
+
+#[derive(Debug)]
+pub enum Error {
+    NotPresent,
+    NoSuchTimer,
+    NoPeriodicMode,
+    NoRoute,
+    NoVector,
+}
+
+pub struct Hpet {
+    address: usize,
+    frequency: u64,
+    timers: usize,
+    /// Counter is 32-bit otherwise
+    wide: bool,
+}
+
+static mut HPET: Hpet = Hpet { address: 0, frequency: 0, timers: 0, wide: false };
+
+fn timer_config(n: usize) -> usize {
+    0x100 + n * 0x20
+}
+
+fn timer_comparator(n: usize) -> usize {
+    0x108 + n * 0x20
+}
+
+impl Hpet {
+    #[inline]
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { read_volatile((self.address + reg) as *const u64) }
+    }
+
+    #[inline]
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { write_volatile((self.address + reg) as *mut u64, value) }
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(REG_COUNTER)
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * self.frequency as u128 / NSEC_PER_SEC as u128) as u64
+    }
+
+    /// Connects comparator `n` to the highest free I/O APIC input
+    /// it can use and delivers it as `vec` to the BSP, returns the GSI
+    fn route(&self, n: usize, vec: usize) -> Result<u32, Error> {
+        let config = self.read(timer_config(n));
+        let allowed = (config >> 32) as u32;
+
+        let gsi = (0 .. 32).rev().find(|&gsi| allowed & (1 << gsi) != 0 && ioapic::is_free(gsi))
+            .ok_or(Error::NoRoute)?;
+        self.write(timer_config(n),
+                   (config & !TIMER_ROUTE_MASK) | ((gsi as u64) << TIMER_ROUTE_SHIFT));
+        ioapic::route_gsi(gsi, irq::idt_vector(vec), 0, Polarity::High, Trigger::Edge)
+            .map_err(|_| Error::NoRoute)?;
+        Ok(gsi)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        self.counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+/// Returns the HPET if one was found during ACPI init
+pub fn get() -> Option<&'static Hpet> {
+    let hpet = unsafe { &HPET };
+    if hpet.address != 0 {
+        Some(hpet)
+    } else {
+        None
+    }
+}
+
+/// Returns the HPET if its counter is wide enough not to
+/// wrap, i.e. it can drive the monotonic clock
+pub fn clock_source() -> Option<&'static Hpet> {
+    get().filter(|hpet| hpet.wide)
+}
+
+/// Busy-waits for `us` microseconds, does nothing if
+/// there's no HPET
+pub fn delay_us(us: u32) {
+    if let Some(hpet) = get() {
+        let mask = if hpet.wide { !0 } else { 0xFFFFFFFF };
+        let ticks = hpet.ns_to_ticks(us as u64 * 1000);
+        let start = hpet.counter();
+
+        while hpet.counter().wrapping_sub(start) & mask < ticks {
+            unsafe { llvm_asm!("pause"); }
+        }
+    }
+}
+
+/// Routes comparator `n` interrupts to `handler`,
+/// returns the GSI used
+pub fn setup_timer(n: usize, handler: &'static mut dyn IrqHandler) -> Result<u32, Error> {
+    let hpet = get().ok_or(Error::NotPresent)?;
+    if n >= hpet.timers {
+        return Err(Error::NoSuchTimer);
+    }
+
+    let vec = irq::alloc_vector().ok_or(Error::NoVector)?;
+    let gsi = hpet.route(n, vec).map_err(|err| {
+        irq::free_vector(vec);
+        err
+    })?;
+    // Comparator stays disabled until armed
+    irq::add(vec, handler);
+    Ok(gsi)
+}
+
+/// Fires comparator `n` interrupt in `ns` nanoseconds and,
+/// if `periodic`, every `ns` nanoseconds after that
+pub fn arm_timer(n: usize, ns: u64, periodic: bool) -> Result<(), Error> {
+    let hpet = get().ok_or(Error::NotPresent)?;
+    if n >= hpet.timers {
+        return Err(Error::NoSuchTimer);
+    }
+    let config = hpet.read(timer_config(n)) & !(TIMER_PERIODIC | TIMER_INT_ENABLE);
+    let ticks = hpet.ns_to_ticks(ns);
+
+    if periodic {
+        if config & TIMER_PERIODIC_CAP == 0 {
+            return Err(Error::NoPeriodicMode);
+        }
+        // The first comparator write sets the deadline,
+        // the next one sets the period
+        hpet.write(timer_config(n), config | TIMER_PERIODIC | TIMER_VALUE_SET);
+        hpet.write(timer_comparator(n), hpet.counter() + ticks);
+        hpet.write(timer_comparator(n), ticks);
+        hpet.write(timer_config(n), config | TIMER_PERIODIC | TIMER_INT_ENABLE);
+    } else {
+        hpet.write(timer_config(n), config);
+        hpet.write(timer_comparator(n), hpet.counter() + ticks);
+        hpet.write(timer_config(n), config | TIMER_INT_ENABLE);
+    }
+    Ok(())
+}
+
+pub fn stop_timer(n: usize) {
+    if let Some(hpet) = get() {
+        if n < hpet.timers {
+            let config = hpet.read(timer_config(n));
+            hpet.write(timer_config(n), config & !(TIMER_PERIODIC | TIMER_INT_ENABLE));
+        }
+    }
+}
+
+pub fn init(address: usize) {
+    let mut hpet = Hpet { address, frequency: 0, timers: 0, wide: false };
+    let caps = hpet.read(REG_CAPS);
+    let period = caps >> 32;
+
+    if period == 0 || period > MAX_PERIOD_FS {
+        println!("HPET period is invalid: {} fs", period);
+        return;
+    }
+    hpet.frequency = FSEC_PER_SEC / period;
+    hpet.timers = ((caps >> 8) & 0x1F) as usize + 1;
+    hpet.wide = caps & CAPS_COUNT_64 != 0;
+
+    // Comparators are off until armed
+    for n in 0 .. hpet.timers {
+        let config = hpet.read(timer_config(n));
+        hpet.write(timer_config(n), config & !(TIMER_PERIODIC | TIMER_INT_ENABLE));
+    }
+
+    // Restart the counter from zero, without legacy replacement
+    let config = hpet.read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY);
+    hpet.write(REG_CONFIG, config);
+    hpet.write(REG_COUNTER, 0);
+    hpet.write(REG_CONFIG, config | CONFIG_ENABLE);
+
+    println!("HPET at 0x{:016x}: {} kHz, {} timers, {}-bit",
+             address, hpet.frequency / 1000, hpet.timers, if hpet.wide { 64 } else { 32 });
+    unsafe { HPET = hpet; }
+}
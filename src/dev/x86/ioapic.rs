@@ -1,9 +1,75 @@
+//! I/O APICs, each handling a range of GSIs starting at
+//! its base
+
+use crate::arch::x86::smp;
+use alloc::vec::Vec;
 use core::ptr::{write_volatile, read_volatile};
 use spin::Mutex;
 
 pub struct IoApic {
     address: usize,
-    limit: usize        // Maximum GSI number
+    gsi_base: u32,
+    limit: usize,       // Maximum redirection entry number
+    /// Bitmap of routed redirection entries
+    used: [u64; 4],
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Error {
+    NoSuchGsi,
+    NoSuchCpu,
+    /// Already routed somewhere
+    GsiInUse,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Polarity {
+    High,
+    Low
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Trigger {
+    Edge,
+    Level
+}
+
+impl Polarity {
+    /// Decodes MPS INTI flags, bus default is ISA's
+    pub fn from_inti(flags: u16) -> Polarity {
+        if flags & 0x3 == 0x3 { Polarity::Low } else { Polarity::High }
+    }
+}
+
+impl Trigger {
+    /// Decodes MPS INTI flags, bus default is ISA's
+    pub fn from_inti(flags: u16) -> Trigger {
+        if (flags >> 2) & 0x3 == 0x3 { Trigger::Level } else { Trigger::Edge }
+    }
+}
+
+const REDIR_NMI: u32        = 4 << 8;
+const REDIR_ACTIVE_LOW: u32 = 1 << 13;
+const REDIR_LEVEL: u32      = 1 << 15;
+const REDIR_MASKED: u32     = 1 << 16;
+
+/// Where an ISA IRQ is connected, if MADT overrides it
+#[derive(Clone, Copy)]
+struct IsaOverride {
+    gsi: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+}
+
+fn redir_mode(polarity: Polarity, trigger: Trigger) -> u32 {
+    let mut mode = 0;
+    if polarity == Polarity::Low {
+        mode |= REDIR_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        mode |= REDIR_LEVEL;
+    }
+    mode
 }
 
 pub struct RedirEntry {
@@ -51,34 +117,145 @@ impl IoApic {
 
     pub fn set_masked(&mut self, idx: usize, masked: bool) {
         if masked {
-            self.write_redir(idx, 0, self.read_redir(idx, 0) | (1 << 16));
+            self.write_redir(idx, 0, self.read_redir(idx, 0) | REDIR_MASKED);
         } else {
-            self.write_redir(idx, 0, self.read_redir(idx, 0) & !(1 << 16));
+            self.write_redir(idx, 0, self.read_redir(idx, 0) & !REDIR_MASKED);
+        }
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.used[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    /// Marks entry `idx` as routed, `false` if it already was
+    fn take(&mut self, idx: usize) -> bool {
+        let taken = !self.is_used(idx);
+        self.used[idx / 64] |= 1 << (idx % 64);
+        taken
+    }
+
+    /// Redirection entry index of `gsi`, if it's handled here
+    fn entry(&self, gsi: u32) -> Option<usize> {
+        if gsi >= self.gsi_base && (gsi - self.gsi_base) as usize <= self.limit {
+            Some((gsi - self.gsi_base) as usize)
+        } else {
+            None
         }
     }
 
     fn init(&mut self) {
         let tmp = self.read(Reg::VER);
-        self.limit = ((tmp >> 16) & 0xFFFF) as usize;
+        self.limit = ((tmp >> 16) & 0xFF) as usize;
 
-        println!("Max GSI number: {}", self.limit);
+        println!("GSIs {} .. {}", self.gsi_base, self.gsi_base as usize + self.limit);
 
         // Mask all GSI
-        for i in 0 .. self.limit {
+        for i in 0 ..= self.limit {
             self.set_masked(i, true);
         }
-
-        self.write_redir(1, 0, 33);
     }
 }
 
-static IOAPIC: Mutex<IoApic> = Mutex::new(IoApic { address: 0, limit: 0 });
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+static ISA_OVERRIDES: Mutex<[Option<IsaOverride>; 16]> = Mutex::new([None; 16]);
+
+/// Runs `f` with the I/O APIC handling `gsi` and its entry index
+fn with_gsi<R, F: FnOnce(&mut IoApic, usize) -> R>(gsi: u32, f: F) -> Result<R, Error> {
+    let mut ioapics = IOAPICS.lock();
+    for ioapic in ioapics.iter_mut() {
+        if let Some(idx) = ioapic.entry(gsi) {
+            return Ok(f(ioapic, idx));
+        }
+    }
+    Err(Error::NoSuchGsi)
+}
 
-pub fn init(address: usize) {
+pub fn init(address: usize, gsi_base: u32) {
     println!("I/O APIC base is 0x{:016x}", address);
-    *IOAPIC.lock() = IoApic {
+    let mut ioapic = IoApic {
         address: address,
-        limit: 0
+        gsi_base: gsi_base,
+        limit: 0,
+        used: [0; 4],
     };
-    IOAPIC.lock().init();
+    ioapic.init();
+    IOAPICS.lock().push(ioapic);
+}
+
+pub fn is_present() -> bool {
+    !IOAPICS.lock().is_empty()
+}
+
+pub fn has_gsi(gsi: u32) -> bool {
+    with_gsi(gsi, |_, _| ()).is_ok()
+}
+
+/// Whether `gsi` exists, isn't routed yet and no ISA IRQ is
+/// connected to it
+pub fn is_free(gsi: u32) -> bool {
+    if (0 .. 16).any(|irq| isa_gsi(irq).0 == gsi) {
+        return false;
+    }
+    with_gsi(gsi, |ioapic, idx| !ioapic.is_used(idx)).unwrap_or(false)
+}
+
+/// Delivers `gsi` to CPU `cpu` as IDT `vector`, unmasked. Each
+/// GSI can only be routed once
+pub fn route_gsi(gsi: u32, vector: u32, cpu: u32, polarity: Polarity, trigger: Trigger) -> Result<(), Error> {
+    if cpu >= smp::cpu_count() {
+        return Err(Error::NoSuchCpu);
+    }
+    let apic_id = smp::apic_id(cpu);
+    with_gsi(gsi, |ioapic, idx| {
+        if !ioapic.take(idx) {
+            return Err(Error::GsiInUse);
+        }
+        ioapic.write_redir(idx, 1, apic_id << 24);
+        ioapic.write_redir(idx, 0, vector | redir_mode(polarity, trigger));
+        Ok(())
+    })?
+}
+
+pub fn set_masked(gsi: u32, masked: bool) -> Result<(), Error> {
+    with_gsi(gsi, |ioapic, idx| ioapic.set_masked(idx, masked))
+}
+
+pub fn mask(gsi: u32) -> Result<(), Error> {
+    set_masked(gsi, true)
+}
+
+pub fn unmask(gsi: u32) -> Result<(), Error> {
+    set_masked(gsi, false)
+}
+
+/// Records an interrupt source override from MADT
+pub fn set_isa_override(irq: u8, gsi: u32, polarity: Polarity, trigger: Trigger) {
+    ISA_OVERRIDES.lock()[irq as usize] = Some(IsaOverride { gsi, polarity, trigger });
+}
+
+/// GSI, polarity and trigger mode of an ISA IRQ
+pub fn isa_gsi(irq: u8) -> (u32, Polarity, Trigger) {
+    match ISA_OVERRIDES.lock()[irq as usize] {
+        Some(o) => (o.gsi, o.polarity, o.trigger),
+        None => (irq as u32, Polarity::High, Trigger::Edge)
+    }
+}
+
+/// Delivers ISA `irq` as `vector`, wherever it's connected
+pub fn route_isa(irq: u8, vector: u32, cpu: u32) -> Result<(), Error> {
+    let (gsi, polarity, trigger) = isa_gsi(irq);
+    route_gsi(gsi, vector, cpu, polarity, trigger)
+}
+
+/// Makes `gsi` raise an NMI on the BSP
+pub fn set_nmi(gsi: u32, polarity: Polarity, trigger: Trigger) -> Result<(), Error> {
+    let apic_id = smp::apic_id(0);
+    with_gsi(gsi, |ioapic, idx| {
+        if !ioapic.take(idx) {
+            return Err(Error::GsiInUse);
+        }
+        ioapic.write_redir(idx, 1, apic_id << 24);
+        ioapic.write_redir(idx, 0, REDIR_NMI | redir_mode(polarity, trigger));
+        Ok(())
+    })?
 }
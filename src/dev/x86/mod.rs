@@ -8,5 +8,9 @@ pub use ioapic::IoApic;
 pub mod acpi;
 
 pub mod ps2;
+pub mod pit;
+pub mod pic;
+pub mod hpet;
+pub mod rtc;
 
 pub mod irq;
@@ -0,0 +1,79 @@
+//! 8259 programmable interrupt controllers. With I/O APIC
+//! they're only moved away from exception vectors and masked,
+//! without ACPI they deliver legacy IRQs as vectors 32 .. 47
+
+use crate::dev::{inb, irq, outb};
+use crate::dev::x86::apic;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MASTER_CMD: u16   = 0x20;
+const MASTER_DATA: u16  = 0x21;
+const SLAVE_CMD: u16    = 0xA0;
+const SLAVE_DATA: u16   = 0xA1;
+
+const ICW1_INIT: u8     = 0x11;
+const ICW4_8086: u8     = 0x01;
+/// No EOI needed, IRQ handlers only acknowledge the local APIC
+const ICW4_AUTO_EOI: u8 = 0x02;
+
+/// Slave is connected to master's IRQ2
+const CASCADE_IRQ: u8   = 2;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Gives the ports time to settle on older chipsets
+#[inline(always)]
+unsafe fn io_wait() {
+    outb(0x80, 0);
+}
+
+pub fn set_masked(irq: u8, masked: bool) {
+    let (port, bit) = if irq < 8 { (MASTER_DATA, irq) } else { (SLAVE_DATA, irq - 8) };
+    unsafe {
+        let mask = inb(port);
+        outb(port, if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+}
+
+/// Whether legacy IRQs come from the PICs
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Remaps IRQs to vectors 32 .. 47 and masks them all. If
+/// `active`, the PICs are wired to the BSP local APIC
+pub fn init(active: bool) {
+    unsafe {
+        outb(MASTER_CMD, ICW1_INIT);
+        io_wait();
+        outb(SLAVE_CMD, ICW1_INIT);
+        io_wait();
+        // Vector offsets
+        outb(MASTER_DATA, 32);
+        io_wait();
+        outb(SLAVE_DATA, 40);
+        io_wait();
+        // Cascade wiring
+        outb(MASTER_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_IRQ);
+        io_wait();
+        outb(MASTER_DATA, ICW4_8086 | ICW4_AUTO_EOI);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086 | ICW4_AUTO_EOI);
+        io_wait();
+
+        outb(MASTER_DATA, 0xFF);
+        outb(SLAVE_DATA, 0xFF);
+    }
+
+    if active {
+        ACTIVE.store(true, Ordering::Relaxed);
+        // IRQs are hardwired to vectors
+        for vec in 1 .. 16 {
+            irq::reserve_vector(vec);
+        }
+        apic::enable_extint();
+        set_masked(CASCADE_IRQ, false);
+    }
+}
@@ -0,0 +1,51 @@
+//! 8254 programmable interval timer. Channel 2 is used
+//! as a busy-wait delay source for calibrating other timers,
+//! channel 0 drives the tick when there's no local APIC timer
+
+use crate::dev::{inb, outb};
+use core::cmp::min;
+
+pub const FREQUENCY: u64 = 1193182;
+
+/// Longest delay a single countdown can do, ~54ms
+const MAX_CHUNK_US: u32 = 50000;
+
+fn countdown(us: u32) {
+    let count = (FREQUENCY * us as u64 / 1000000) as u16;
+    unsafe {
+        // Gate off, speaker disconnected
+        let ctl = inb(0x61) & !0x03;
+        outb(0x61, ctl);
+        // Channel 2, lobyte/hibyte, interrupt on terminal count
+        outb(0x43, 0xB0);
+        outb(0x42, (count & 0xFF) as u8);
+        outb(0x42, (count >> 8) as u8);
+        // Rising edge of the gate starts counting
+        outb(0x61, ctl | 0x01);
+
+        while inb(0x61) & 0x20 == 0 {
+            llvm_asm!("pause");
+        }
+        outb(0x61, ctl);
+    }
+}
+
+/// Busy-waits for `us` microseconds
+pub fn delay_us(mut us: u32) {
+    while us != 0 {
+        let chunk = min(us, MAX_CHUNK_US);
+        countdown(chunk);
+        us -= chunk;
+    }
+}
+
+/// Raises IRQ0 at `hz` rate
+pub fn start_periodic(hz: u32) {
+    let divisor = min(FREQUENCY / hz as u64, 0xFFFF) as u16;
+    unsafe {
+        // Channel 0, lobyte/hibyte, rate generator
+        outb(0x43, 0x34);
+        outb(0x40, (divisor & 0xFF) as u8);
+        outb(0x40, (divisor >> 8) as u8);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::dev::{irq, io::inb};
+use crate::dev::x86::{ioapic, pic};
 
 struct Keyboard;
 
@@ -38,7 +39,31 @@ const MASTER_DATA:      u16 = 0x60;
 #[allow(dead_code)]
 static mut MASTER: Keyboard = Keyboard {};
 
+/// ISA IRQ of the first PS/2 port
+const IRQ: u8 = 1;
+
 pub fn init() {
+    let _vec = if pic::is_active() {
+        pic::set_masked(IRQ, false);
+        IRQ as usize
+    } else if ioapic::is_present() {
+        let vec = match irq::alloc_vector() {
+            Some(vec) => vec,
+            None => {
+                println!("PS/2 keyboard: no free IRQ vector");
+                return;
+            }
+        };
+        if let Err(err) = ioapic::route_isa(IRQ, irq::idt_vector(vec), 0) {
+            println!("PS/2 keyboard: failed to route IRQ: {:?}", err);
+            irq::free_vector(vec);
+            return;
+        }
+        vec
+    } else {
+        return;
+    };
+
     // Bind irq
-    //irq::add(1, unsafe {&mut MASTER});
+    //irq::add(_vec, unsafe {&mut MASTER});
 }
@@ -0,0 +1,152 @@
+//! CMOS real-time clock, read once at boot to set the
+//! realtime clock
+
+use crate::dev::{PortIo, ReadIo, WriteIo};
+use crate::dev::x86::acpi::FADT;
+use crate::time::{self, DateTime, NSEC_PER_SEC};
+use spin::Mutex;
+
+const REG_SECOND: u8    = 0x00;
+const REG_MINUTE: u8    = 0x02;
+const REG_HOUR: u8      = 0x04;
+const REG_DAY: u8       = 0x07;
+const REG_MONTH: u8     = 0x08;
+const REG_YEAR: u8      = 0x09;
+const REG_STATUS_A: u8  = 0x0A;
+const REG_STATUS_B: u8  = 0x0B;
+
+/// Status A: date/time registers are being updated
+const STATUS_A_UPDATE: u8   = 1 << 7;
+/// Status B: hours are 0 .. 23, 1 .. 12 with PM bit otherwise
+const STATUS_B_24H: u8      = 1 << 1;
+/// Status B: values are binary, BCD otherwise
+const STATUS_B_BINARY: u8   = 1 << 2;
+const HOUR_PM: u8           = 1 << 7;
+
+/// Keeps NMIs disabled while a register is selected
+const NMI_DISABLE: u8 = 1 << 7;
+
+struct Rtc {
+    index: PortIo<u8>,
+    data: PortIo<u8>,
+    /// Century register index from FADT, 0 if none
+    century: u8,
+}
+
+/// Raw register values
+#[derive(PartialEq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+static RTC: Mutex<Rtc> = Mutex::new(Rtc {
+    index: PortIo::new(0x70),
+    data: PortIo::new(0x71),
+    century: 0,
+});
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+impl Rtc {
+    fn read(&mut self, reg: u8) -> u8 {
+        self.index.write(reg | NMI_DISABLE);
+        self.data.read()
+    }
+
+    fn read_registers(&mut self) -> Registers {
+        while self.read(REG_STATUS_A) & STATUS_A_UPDATE != 0 {
+            unsafe { llvm_asm!("pause"); }
+        }
+
+        Registers {
+            second: self.read(REG_SECOND),
+            minute: self.read(REG_MINUTE),
+            hour: self.read(REG_HOUR),
+            day: self.read(REG_DAY),
+            month: self.read(REG_MONTH),
+            year: self.read(REG_YEAR),
+            century: if self.century != 0 { self.read(self.century) } else { 0 },
+        }
+    }
+
+    /// None if the registers hold garbage
+    fn read_date(&mut self) -> Option<DateTime> {
+        // An update may still start while reading, so read
+        // until two reads in a row agree
+        let mut regs = self.read_registers();
+        loop {
+            let again = self.read_registers();
+            if again == regs {
+                break;
+            }
+            regs = again;
+        }
+
+        let status = self.read(REG_STATUS_B);
+        let pm = regs.hour & HOUR_PM != 0;
+        let mut hour = regs.hour & !HOUR_PM;
+
+        if status & STATUS_B_BINARY == 0 {
+            regs.second = from_bcd(regs.second);
+            regs.minute = from_bcd(regs.minute);
+            hour = from_bcd(hour);
+            regs.day = from_bcd(regs.day);
+            regs.month = from_bcd(regs.month);
+            regs.year = from_bcd(regs.year);
+            regs.century = from_bcd(regs.century);
+        }
+        if status & STATUS_B_24H == 0 {
+            // 12 AM is midnight, 12 PM is noon
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
+
+        let year = if regs.century != 0 {
+            regs.century as u32 * 100 + regs.year as u32
+        } else {
+            2000 + regs.year as u32
+        };
+
+        let date = DateTime {
+            year,
+            month: regs.month,
+            day: regs.day,
+            hour,
+            minute: regs.minute,
+            second: regs.second,
+        };
+        if date.is_valid() { Some(date) } else { None }
+    }
+}
+
+pub fn read_date() -> Option<DateTime> {
+    RTC.lock().read_date()
+}
+
+/// Sets the realtime clock from the RTC, after `time::init`
+pub fn init() {
+    let mut rtc = RTC.lock();
+    if let Some(fadt) = &*FADT.lock() {
+        // Century index is at offset 108
+        if fadt.hdr.length as usize > 108 {
+            rtc.century = fadt.century;
+        }
+    }
+
+    let date = rtc.read_date();
+    drop(rtc);
+    match date {
+        Some(date) => {
+            time::set_realtime(date.to_unix() * NSEC_PER_SEC);
+            println!("RTC: {} UTC", date);
+        },
+        None => println!("RTC holds an invalid date, realtime clock is not set")
+    }
+}
@@ -0,0 +1,328 @@
+//! ELF64 executable loader
+
+use crate::mem::{self, PageFlags, Space, MapError, PAGE_SIZE, USER_END};
+use crate::mem::region::{Backing, Region, RegionKind};
+use crate::thread::Process;
+use crate::fs::{File, OpenFlags};
+use crate::virtualize;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::cmp::min;
+use spin::Mutex;
+
+const ET_EXEC: u16      = 2;
+const EM_X86_64: u16    = 62;
+
+const PT_LOAD: u32      = 1;
+
+const PF_X: u32         = 1 << 0;
+const PF_W: u32         = 1 << 1;
+
+const AT_NULL: usize    = 0;
+const AT_PHDR: usize    = 3;
+const AT_PHENT: usize   = 4;
+const AT_PHNUM: usize   = 5;
+const AT_PAGESZ: usize  = 6;
+const AT_ENTRY: usize   = 9;
+
+const MAX_PHNUM: usize  = 64;
+
+/// Top of the main thread stack
+pub const USER_STACK_TOP: usize = 0x00007FFFFFFF0000;
+pub const USER_STACK_PAGES: usize = 16;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// Not an ELF file at all
+    BadMagic,
+    /// Valid ELF, but not a static x86-64 executable
+    Unsupported,
+    /// Headers describe something impossible
+    BadHeader,
+    /// File ended before the headers did
+    Truncated,
+    /// Arguments don't fit into the stack
+    TooBig,
+    Map(MapError),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ehdr {
+    ident:      [u8; 16],
+    ty:         u16,
+    machine:    u16,
+    version:    u32,
+    entry:      u64,
+    phoff:      u64,
+    shoff:      u64,
+    flags:      u32,
+    ehsize:     u16,
+    phentsize:  u16,
+    phnum:      u16,
+    shentsize:  u16,
+    shnum:      u16,
+    shstrndx:   u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Phdr {
+    ty:         u32,
+    flags:      u32,
+    offset:     u64,
+    vaddr:      u64,
+    paddr:      u64,
+    filesz:     u64,
+    memsz:      u64,
+    align:      u64,
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> ElfError {
+        ElfError::Map(err)
+    }
+}
+
+fn read_struct<T: Copy>(source: &dyn Backing, offset: usize) -> Result<T, ElfError> {
+    let mut data = MaybeUninit::<T>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, size_of::<T>())
+    };
+
+    if source.read(offset, buf) != size_of::<T>() {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { data.assume_init() })
+}
+
+impl Ehdr {
+    fn validate(&self) -> Result<(), ElfError> {
+        if self.ident[.. 4] != [0x7F, b'E', b'L', b'F'] {
+            return Err(ElfError::BadMagic);
+        }
+        // 64-bit, little-endian, version 1
+        if self.ident[4] != 2 || self.ident[5] != 1 || self.ident[6] != 1 {
+            return Err(ElfError::Unsupported);
+        }
+        if self.ty != ET_EXEC || self.machine != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+        if self.phentsize as usize != size_of::<Phdr>() || self.phnum as usize > MAX_PHNUM {
+            return Err(ElfError::BadHeader);
+        }
+        if self.entry as usize >= USER_END {
+            return Err(ElfError::BadHeader);
+        }
+        Ok(())
+    }
+}
+
+impl Phdr {
+    /// Checks the segment fits into user space and its data
+    /// into the file of `file_size` bytes
+    fn validate(&self, file_size: usize) -> Result<(), ElfError> {
+        let end = self.vaddr.checked_add(self.memsz).ok_or(ElfError::BadHeader)?;
+        let file_end = self.offset.checked_add(self.filesz).ok_or(ElfError::BadHeader)?;
+
+        if end as usize > USER_END || self.filesz > self.memsz {
+            return Err(ElfError::BadHeader);
+        }
+        if file_end > file_size as u64 {
+            return Err(ElfError::Truncated);
+        }
+        if self.vaddr % PAGE_SIZE as u64 != self.offset % PAGE_SIZE as u64 {
+            return Err(ElfError::BadHeader);
+        }
+        Ok(())
+    }
+
+    /// Region covering the segment, the part of the first page
+    /// before `vaddr` gets preceding file data
+    fn region(&self, source: &Arc<dyn Backing>) -> Region {
+        let skew = self.vaddr as usize & (PAGE_SIZE - 1);
+        let start = self.vaddr as usize - skew;
+        let end = (self.vaddr + self.memsz) as usize;
+
+        let mut flags = PageFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageFlags::WRITE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageFlags::NX;
+        }
+
+        Region::new(start, (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), flags, RegionKind::File {
+            backing: source.clone(),
+            offset: self.offset as usize - skew,
+            size: self.filesz as usize + skew,
+        })
+    }
+}
+
+/// Writes the initial stack through the new space's mappings
+/// (it's not the active one while loading)
+struct StackWriter<'a> {
+    space:  &'a mut Space,
+    region: Region,
+    sp:     usize,
+}
+
+impl<'a> StackWriter<'a> {
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), ElfError> {
+        let mut pos = 0;
+        while pos < data.len() {
+            let virt = addr + pos;
+            let page = virt & !(PAGE_SIZE - 1);
+            let phys = match mem::translate(self.space, page, None) {
+                Some(phys) => phys,
+                None => {
+                    self.region.populate(self.space, page)?;
+                    mem::translate(self.space, page, None).unwrap()
+                }
+            };
+
+            let len = min(data.len() - pos, page + PAGE_SIZE - virt);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[pos ..].as_ptr(),
+                                               (virtualize(phys) + virt - page) as *mut u8,
+                                               len);
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    fn push_str(&mut self, s: &str) -> Result<usize, ElfError> {
+        let len = s.len() + 1;
+        if self.sp - self.region.start < len + PAGE_SIZE {
+            return Err(ElfError::TooBig);
+        }
+
+        self.sp -= len;
+        let sp = self.sp;
+        self.write(sp, s.as_bytes())?;
+        self.write(sp + s.len(), &[0])?;
+        Ok(sp)
+    }
+
+    /// Pushes NULL-terminated arrays of pointers to `args`
+    fn push_strings(&mut self, args: &[&str]) -> Result<Vec<usize>, ElfError> {
+        let mut ptrs = Vec::new();
+        for arg in args.iter() {
+            ptrs.push(self.push_str(arg)?);
+        }
+        ptrs.push(0);
+        Ok(ptrs)
+    }
+}
+
+/// Sets up argc, argv, envp and auxv as expected by SysV ABI,
+/// returns initial stack pointer
+fn setup_stack(space: &mut Space,
+               region: Region,
+               argv: &[&str],
+               envp: &[&str],
+               auxv: &[(usize, usize)]) -> Result<usize, ElfError> {
+    let mut stack = StackWriter {
+        sp: region.end,
+        space,
+        region,
+    };
+
+    let envp = stack.push_strings(envp)?;
+    let argv = stack.push_strings(argv)?;
+
+    let mut words = Vec::new();
+    words.push(argv.len() - 1);
+    words.extend_from_slice(&argv);
+    words.extend_from_slice(&envp);
+    for &(key, value) in auxv.iter() {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    // %rsp has to be 16-byte aligned at entry
+    let size = words.len() * size_of::<usize>();
+    let sp = (stack.sp - size) & !0xF;
+    if sp - stack.region.start < PAGE_SIZE {
+        return Err(ElfError::TooBig);
+    }
+
+    let data = unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, size) };
+    stack.write(sp, data)?;
+    Ok(sp)
+}
+
+fn load_into(process: &mut Process,
+             source: &Arc<dyn Backing>,
+             argv: &[&str],
+             envp: &[&str]) -> Result<(), ElfError> {
+    let ehdr: Ehdr = read_struct(source.as_ref(), 0)?;
+    ehdr.validate()?;
+
+    let mut phdr_addr = None;
+    for i in 0 .. ehdr.phnum as usize {
+        let offset = (ehdr.phoff as usize).checked_add(i * size_of::<Phdr>())
+            .ok_or(ElfError::BadHeader)?;
+        let phdr: Phdr = read_struct(source.as_ref(), offset)?;
+
+        if phdr.ty != PT_LOAD || phdr.memsz == 0 {
+            continue;
+        }
+        phdr.validate(source.size())?;
+
+        // Program headers are loaded as part of this segment,
+        // vaddr + skip is below the validated vaddr + memsz
+        if let Some(skip) = ehdr.phoff.checked_sub(phdr.offset).filter(|&skip| skip < phdr.filesz) {
+            phdr_addr = Some((phdr.vaddr + skip) as usize);
+        }
+
+        process.regions.add(phdr.region(source))?;
+    }
+
+    let stack = Region::new(USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE,
+                            USER_STACK_TOP,
+                            PageFlags::WRITE | PageFlags::NX,
+                            RegionKind::Stack);
+    process.regions.add(stack.clone())?;
+
+    let mut auxv = Vec::new();
+    if let Some(addr) = phdr_addr {
+        auxv.push((AT_PHDR, addr));
+    }
+    auxv.push((AT_PHENT, size_of::<Phdr>()));
+    auxv.push((AT_PHNUM, ehdr.phnum as usize));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, ehdr.entry as usize));
+
+    let sp = setup_stack(process.space, stack, argv, envp, &auxv)?;
+    process.spawn_user(ehdr.entry as usize, sp);
+    Ok(())
+}
+
+/// Creates a new process running the executable from `source`.
+/// Returns its PID
+pub fn load(source: Arc<dyn Backing>, argv: &[&str], envp: &[&str]) -> Result<i32, ElfError> {
+    let process = Process::new_user().ok_or(ElfError::Map(MapError::OutOfMemory))?;
+    let mut process = Box::new(process);
+
+    // Standard streams
+    if let Ok(console) = File::open("/", "/dev/console", OpenFlags::READ_WRITE) {
+        let console = Arc::new(Mutex::new(console));
+        for _ in 0 .. 3 {
+            process.files.insert(console.clone()).unwrap();
+        }
+    }
+
+    // Dropping the process on error releases everything mapped so far
+    load_into(&mut process, &source, argv, envp)?;
+
+    let process = Box::leak(process);
+    println!("Loaded executable into process #{}", process.id);
+    Ok(process.id)
+}
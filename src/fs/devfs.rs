@@ -0,0 +1,84 @@
+//! Device filesystem usually mounted at /dev
+
+use super::{DirEntry, Error, Stat, Vnode, VnodeKind};
+use crate::dev::{x86::COM1, SerialDevice};
+use crate::sync::IrqDisable;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Device {
+    Null,
+    Zero,
+    Console,
+}
+
+const DEVICES: [(&str, Device); 3] = [
+    ("null",    Device::Null),
+    ("zero",    Device::Zero),
+    ("console", Device::Console),
+];
+
+pub struct Root;
+struct Node(Device);
+
+fn stat(kind: VnodeKind, mode: u32) -> Stat {
+    Stat {
+        kind,
+        mode,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        mtime: 0,
+    }
+}
+
+impl Vnode for Root {
+    fn stat(&self) -> Stat {
+        stat(VnodeKind::Directory, 0o755)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Error> {
+        DEVICES.iter()
+            .find(|(dev_name, _)| *dev_name == name)
+            .map(|&(_, dev)| Arc::new(Node(dev)) as Arc<dyn Vnode>)
+            .ok_or(Error::NotFound)
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        Ok(DEVICES.get(index).map(|(name, _)| DirEntry {
+            name: String::from(*name),
+            kind: VnodeKind::CharDevice,
+        }))
+    }
+}
+
+impl Vnode for Node {
+    fn stat(&self) -> Stat {
+        stat(VnodeKind::CharDevice, 0o666)
+    }
+
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.0 {
+            Device::Zero => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(buf.len())
+            },
+            // TODO: console input
+            Device::Null | Device::Console => Ok(0)
+        }
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        if self.0 == Device::Console {
+            let _lock = IrqDisable::new();
+            let mut port = COM1.lock();
+            for &byte in buf {
+                port.tx(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+}
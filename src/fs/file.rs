@@ -0,0 +1,189 @@
+//! Open files and per-process descriptor tables
+
+use super::{vfs, DirEntry, Error, Stat, Vnode, VnodeKind};
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const MAX_FILES: usize = 64;
+
+bitflags! {
+    /// Linux-compatible `open()` flags
+    pub struct OpenFlags: u32 {
+        const WRITE_ONLY    = 0o1;
+        const READ_WRITE    = 0o2;
+        const APPEND        = 0o2000;
+        const DIRECTORY     = 0o200000;
+        const NO_FOLLOW     = 0o400000;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+/// Open file description, shared by duplicated descriptors
+pub struct File {
+    vnode:  Arc<dyn Vnode>,
+    flags:  OpenFlags,
+    offset: usize,
+}
+
+pub type FileRef = Arc<Mutex<File>>;
+
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<FileRef>>,
+}
+
+impl OpenFlags {
+    pub fn readable(&self) -> bool {
+        !self.contains(OpenFlags::WRITE_ONLY)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.intersects(OpenFlags::WRITE_ONLY | OpenFlags::READ_WRITE)
+    }
+}
+
+impl File {
+    pub fn open(cwd: &str, path: &str, flags: OpenFlags) -> Result<File, Error> {
+        let (_, vnode) = vfs::lookup(cwd, path, !flags.contains(OpenFlags::NO_FOLLOW))?;
+        let kind = vnode.stat().kind;
+
+        if kind == VnodeKind::Symlink {
+            return Err(Error::Loop);
+        }
+        if kind == VnodeKind::Directory && flags.writable() {
+            return Err(Error::IsDirectory);
+        }
+        if kind != VnodeKind::Directory && flags.contains(OpenFlags::DIRECTORY) {
+            return Err(Error::NotDirectory);
+        }
+
+        Ok(File {
+            vnode,
+            flags,
+            offset: 0,
+        })
+    }
+
+    pub fn stat(&self) -> Stat {
+        self.vnode.stat()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if !self.flags.readable() {
+            return Err(Error::BadDescriptor);
+        }
+
+        let count = self.vnode.read(self.offset, buf)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if !self.flags.writable() {
+            return Err(Error::BadDescriptor);
+        }
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.offset = self.vnode.stat().size;
+        }
+
+        let count = self.vnode.write(self.offset, buf)?;
+        self.offset += count;
+        Ok(count)
+    }
+
+    /// Returns the next directory entry, `None` at the end
+    pub fn readdir(&mut self) -> Result<Option<DirEntry>, Error> {
+        let entry = self.vnode.readdir(self.offset)?;
+        if entry.is_some() {
+            self.offset += 1;
+        }
+        Ok(entry)
+    }
+
+    pub fn seek(&mut self, offset: isize, whence: Whence) -> Result<usize, Error> {
+        let base = match whence {
+            Whence::Set     => 0,
+            Whence::Current => self.offset,
+            Whence::End     => self.vnode.stat().size,
+        };
+
+        let offset = if offset < 0 {
+            base.checked_sub(offset.wrapping_neg() as usize)
+        } else {
+            base.checked_add(offset as usize)
+        }.ok_or(Error::InvalidArgument)?;
+
+        // Directory offset is an entry index, so its end is unknown
+        if self.vnode.stat().kind == VnodeKind::Directory && whence == Whence::End {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+impl FdTable {
+    pub const fn new() -> FdTable {
+        FdTable {
+            files: Vec::new()
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Result<FileRef, Error> {
+        self.files.get(fd).and_then(|f| f.clone()).ok_or(Error::BadDescriptor)
+    }
+
+    /// Installs `file` at the lowest free descriptor
+    pub fn insert(&mut self, file: FileRef) -> Result<usize, Error> {
+        let fd = self.files.iter().position(|f| f.is_none()).unwrap_or(self.files.len());
+        if fd >= MAX_FILES {
+            return Err(Error::TooManyFiles);
+        }
+
+        if fd == self.files.len() {
+            self.files.push(Some(file));
+        } else {
+            self.files[fd] = Some(file);
+        }
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), Error> {
+        match self.files.get_mut(fd) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                Ok(())
+            },
+            _ => Err(Error::BadDescriptor)
+        }
+    }
+
+    pub fn dup(&mut self, fd: usize) -> Result<usize, Error> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    /// Makes `new_fd` refer to the same file as `fd`,
+    /// closing whatever it referred to before
+    pub fn dup2(&mut self, fd: usize, new_fd: usize) -> Result<usize, Error> {
+        let file = self.get(fd)?;
+        if new_fd >= MAX_FILES {
+            return Err(Error::BadDescriptor);
+        }
+
+        if new_fd >= self.files.len() {
+            self.files.resize(new_fd + 1, None);
+        }
+        self.files[new_fd] = Some(file);
+        Ok(new_fd)
+    }
+}
@@ -0,0 +1,476 @@
+//! Read-only access to the initial ramdisk passed by the
+//! loader. Both USTAR and "newc" cpio archives are supported,
+//! file contents are used in place
+
+use super::{DirEntry, Stat, Vnode, VnodeKind};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::str;
+
+pub struct Entry {
+    /// Path relative to archive root, with no leading
+    /// or trailing slashes
+    pub path:   String,
+    stat:       Stat,
+    data:       &'static [u8],
+    /// Symlink target
+    link:       &'static str,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    UnknownFormat,
+    BadHeader,
+    Truncated,
+}
+
+static mut ENTRIES: Vec<Entry> = Vec::new();
+
+const TAR_BLOCK: usize  = 512;
+
+const S_IFMT: u32       = 0o170000;
+const S_IFDIR: u32      = 0o040000;
+const S_IFREG: u32      = 0o100000;
+const S_IFLNK: u32      = 0o120000;
+
+impl Entry {
+    pub fn stat(&self) -> Stat {
+        self.stat
+    }
+
+    /// Whole file contents, empty for anything but regular files
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    pub fn link(&self) -> Option<&'static str> {
+        if self.stat.kind == VnodeKind::Symlink {
+            Some(self.link)
+        } else {
+            None
+        }
+    }
+
+    /// Reads file data at `offset` into `buf`, returns the
+    /// number of bytes read
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.data.len() {
+            return 0;
+        }
+        let len = min(buf.len(), self.data.len() - offset);
+        buf[.. len].copy_from_slice(&self.data[offset .. offset + len]);
+        len
+    }
+}
+
+/// Strips "./", leading and trailing slashes
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if path.starts_with("./") {
+            path = &path[2 ..];
+        } else if path.starts_with('/') {
+            path = &path[1 ..];
+        } else {
+            break;
+        }
+    }
+    let path = path.trim_end_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+/// NUL-terminated string from a fixed-size field
+fn field_str(data: &'static [u8]) -> Result<&'static str, Error> {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    str::from_utf8(&data[.. len]).map_err(|_| Error::BadHeader)
+}
+
+fn parse_number(data: &[u8], radix: u32) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for &byte in data {
+        match byte {
+            // Octal tar fields are padded with spaces and NULs
+            0 | b' ' if radix == 8 => continue,
+            _ => {}
+        }
+        let digit = (byte as char).to_digit(radix).ok_or(Error::BadHeader)?;
+        value = value.checked_mul(radix as u64).ok_or(Error::BadHeader)? + digit as u64;
+    }
+    Ok(value)
+}
+
+fn slice(data: &'static [u8], offset: usize, len: usize) -> Result<&'static [u8], Error> {
+    let end = offset.checked_add(len).ok_or(Error::Truncated)?;
+    data.get(offset .. end).ok_or(Error::Truncated)
+}
+
+fn parse_tar(data: &'static [u8]) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let hdr = slice(data, offset, TAR_BLOCK)?;
+        // Archive ends with zero blocks
+        if hdr.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let checksum = parse_number(&hdr[148 .. 156], 8)?;
+        let sum = hdr.iter().enumerate()
+            .map(|(i, &b)| if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 })
+            .sum::<u64>();
+        if sum != checksum {
+            return Err(Error::BadHeader);
+        }
+
+        let size = parse_number(&hdr[124 .. 136], 8)? as usize;
+        let body = slice(data, offset + TAR_BLOCK, size)?;
+        offset += TAR_BLOCK + ((size + TAR_BLOCK - 1) & !(TAR_BLOCK - 1));
+
+        let kind = match hdr[156] {
+            0 | b'0' | b'7' => VnodeKind::Regular,
+            b'5'            => VnodeKind::Directory,
+            b'2'            => VnodeKind::Symlink,
+            // Hard links, devices and extended headers are not supported
+            _               => continue,
+        };
+
+        let name = field_str(&hdr[0 .. 100])?;
+        let prefix = field_str(&hdr[345 .. 500])?;
+        let mut path = String::new();
+        if !prefix.is_empty() {
+            path.push_str(normalize(prefix));
+            path.push('/');
+        }
+        path.push_str(name);
+
+        let path = String::from(normalize(&path));
+        if path.is_empty() {
+            continue;
+        }
+
+        entries.push(Entry {
+            path,
+            stat: Stat {
+                kind,
+                mode:   parse_number(&hdr[100 .. 108], 8)? as u32 & 0o7777,
+                uid:    parse_number(&hdr[108 .. 116], 8)? as u32,
+                gid:    parse_number(&hdr[116 .. 124], 8)? as u32,
+                size:   if kind == VnodeKind::Regular { size } else { 0 },
+                mtime:  parse_number(&hdr[136 .. 148], 8)?,
+            },
+            data: if kind == VnodeKind::Regular { body } else { &[] },
+            link: field_str(&hdr[157 .. 257])?,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn parse_cpio(data: &'static [u8]) -> Result<Vec<Entry>, Error> {
+    const HEADER_SIZE: usize = 110;
+    let align = |x: usize| (x + 3) & !3;
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let hdr = slice(data, offset, HEADER_SIZE)?;
+        if &hdr[.. 6] != b"070701" && &hdr[.. 6] != b"070702" {
+            return Err(Error::BadHeader);
+        }
+        // 13 8-digit hex fields follow the magic
+        let field = |i: usize| parse_number(&hdr[6 + i * 8 .. 14 + i * 8], 16);
+
+        let mode = field(1)? as u32;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name = slice(data, offset + HEADER_SIZE, name_size)?;
+        let name = field_str(name)?;
+        let data_offset = align(offset + HEADER_SIZE + name_size);
+        let body = slice(data, data_offset, size)?;
+        offset = align(data_offset + size);
+
+        if name == "TRAILER!!!" {
+            break;
+        }
+
+        let path = normalize(name);
+        if path.is_empty() {
+            continue;
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => VnodeKind::Regular,
+            S_IFDIR => VnodeKind::Directory,
+            S_IFLNK => VnodeKind::Symlink,
+            _       => continue,
+        };
+        let link = if kind == VnodeKind::Symlink {
+            str::from_utf8(body).map_err(|_| Error::BadHeader)?
+        } else {
+            ""
+        };
+
+        entries.push(Entry {
+            path: String::from(path),
+            stat: Stat {
+                kind,
+                mode:   mode & 0o7777,
+                uid:    field(2)? as u32,
+                gid:    field(3)? as u32,
+                size:   if kind == VnodeKind::Regular { size } else { 0 },
+                mtime:  field(5)?,
+            },
+            data: if kind == VnodeKind::Regular { body } else { &[] },
+            link,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Detects archive format and lists its entries
+fn parse(data: &'static [u8]) -> Result<Vec<Entry>, Error> {
+    if data.starts_with(b"070701") || data.starts_with(b"070702") {
+        parse_cpio(data)
+    } else if data.len() >= TAR_BLOCK && &data[257 .. 262] == b"ustar" {
+        parse_tar(data)
+    } else {
+        Err(Error::UnknownFormat)
+    }
+}
+
+/// Looks up an entry by its path. Symlinks are not followed
+pub fn lookup(path: &str) -> Option<&'static Entry> {
+    let path = normalize(path);
+    entries().iter().find(|e| e.path == path)
+}
+
+pub fn entries() -> &'static [Entry] {
+    unsafe { &ENTRIES }
+}
+
+/// Parses the archive at virtual address `base`. The memory
+/// has to stay reserved as file data is never copied
+pub fn init(base: usize, size: usize) {
+    let data = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+
+    match parse(data) {
+        Ok(entries) => {
+            println!("initrd: {} entries, {}K", entries.len(), size / 1024);
+            unsafe { ENTRIES = entries; }
+        },
+        Err(err) => println!("initrd: failed to parse archive: {:?}", err)
+    }
+}
+
+/// Directory of the archive, either listed in it or implied
+/// by paths of its entries
+pub struct Dir {
+    path:   String,
+    entry:  Option<&'static Entry>,
+}
+
+struct Node(&'static Entry);
+
+impl Dir {
+    pub fn root() -> Dir {
+        Dir {
+            path: String::new(),
+            entry: None,
+        }
+    }
+
+    fn prefix(&self) -> String {
+        let mut prefix = self.path.clone();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix
+    }
+}
+
+impl Vnode for Dir {
+    fn stat(&self) -> Stat {
+        match self.entry {
+            Some(entry) => entry.stat,
+            None => Stat {
+                kind: VnodeKind::Directory,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                mtime: 0,
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, super::Error> {
+        let mut path = self.prefix();
+        path.push_str(name);
+
+        if let Some(entry) = entries().iter().find(|e| e.path == path) {
+            return Ok(if entry.stat.kind == VnodeKind::Directory {
+                Arc::new(Dir { path, entry: Some(entry) })
+            } else {
+                Arc::new(Node(entry))
+            });
+        }
+
+        path.push('/');
+        if entries().iter().any(|e| e.path.starts_with(&path)) {
+            path.pop();
+            Ok(Arc::new(Dir { path, entry: None }))
+        } else {
+            Err(super::Error::NotFound)
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Result<Option<DirEntry>, super::Error> {
+        let prefix = self.prefix();
+        let mut names: Vec<(&str, VnodeKind)> = Vec::new();
+
+        for entry in entries() {
+            if !entry.path.starts_with(&prefix) {
+                continue;
+            }
+            let rest = &entry.path[prefix.len() ..];
+            let (name, kind) = match rest.find('/') {
+                Some(pos) => (&rest[.. pos], VnodeKind::Directory),
+                None => (rest, entry.stat.kind)
+            };
+
+            if !names.iter().any(|(n, _)| *n == name) {
+                if names.len() == index {
+                    return Ok(Some(DirEntry {
+                        name: String::from(name),
+                        kind,
+                    }));
+                }
+                names.push((name, kind));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Vnode for Node {
+    fn stat(&self) -> Stat {
+        self.0.stat
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, super::Error> {
+        Ok(self.0.read(offset, buf))
+    }
+
+    fn readlink(&self) -> Result<String, super::Error> {
+        self.0.link().map(String::from).ok_or(super::Error::InvalidArgument)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Error, VnodeKind};
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use std::format;
+
+    fn cpio_entry(buf: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let header = format!("070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                             1, mode, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0);
+        buf.extend_from_slice(header.as_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+        buf.extend_from_slice(data);
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+    }
+
+    fn tar_entry(buf: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        let mut hdr = [0u8; 512];
+        hdr[.. name.len()].copy_from_slice(name.as_bytes());
+        hdr[100 .. 107].copy_from_slice(b"0000644");
+        hdr[124 .. 135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        hdr[136 .. 147].copy_from_slice(b"00000000000");
+        hdr[156] = kind;
+        hdr[257 .. 263].copy_from_slice(b"ustar\0");
+        hdr[263 .. 265].copy_from_slice(b"00");
+        for byte in &mut hdr[148 .. 156] {
+            *byte = b' ';
+        }
+        let sum = hdr.iter().map(|&b| b as u32).sum::<u32>();
+        hdr[148 .. 155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+        buf.extend_from_slice(&hdr);
+        buf.extend_from_slice(data);
+        while buf.len() % 512 != 0 {
+            buf.push(0);
+        }
+    }
+
+    fn leak(buf: Vec<u8>) -> &'static [u8] {
+        Box::leak(buf.into_boxed_slice())
+    }
+
+    #[test]
+    fn cpio() {
+        let mut buf = Vec::new();
+        cpio_entry(&mut buf, ".", 0o040755, b"");
+        cpio_entry(&mut buf, "bin", 0o040755, b"");
+        cpio_entry(&mut buf, "bin/init", 0o100755, b"\x7FELF...");
+        cpio_entry(&mut buf, "sh", 0o120777, b"bin/init");
+        cpio_entry(&mut buf, "TRAILER!!!", 0, b"");
+
+        let entries = parse(leak(buf)).unwrap();
+        assert!(entries.len() == 3);
+        assert!(entries[0].path == "bin" && entries[0].stat().kind == VnodeKind::Directory);
+        assert!(entries[1].path == "bin/init" && entries[1].data() == b"\x7FELF...");
+        assert!(entries[1].stat().mode == 0o755 && entries[1].stat().size == 7);
+        assert!(entries[2].link() == Some("bin/init"));
+
+        let mut tmp = [0u8; 4];
+        assert!(entries[1].read(4, &mut tmp) == 3 && &tmp[.. 3] == b"...");
+        assert!(entries[1].read(7, &mut tmp) == 0);
+    }
+
+    #[test]
+    fn tar() {
+        let mut buf = Vec::new();
+        tar_entry(&mut buf, "./etc/", b'5', b"");
+        tar_entry(&mut buf, "./etc/motd", b'0', &[b'x'; 600]);
+        buf.extend_from_slice(&[0; 1024]);
+
+        let entries = parse(leak(buf)).unwrap();
+        assert!(entries.len() == 2);
+        assert!(entries[0].path == "etc" && entries[0].stat().kind == VnodeKind::Directory);
+        assert!(entries[1].path == "etc/motd" && entries[1].data().len() == 600);
+    }
+
+    #[test]
+    fn bad_archives() {
+        assert!(parse(leak(b"garbage".to_vec())).err() == Some(Error::UnknownFormat));
+
+        let mut buf = Vec::new();
+        tar_entry(&mut buf, "file", b'0', b"data");
+        buf[0] = b'F';
+        assert!(parse(leak(buf)).err() == Some(Error::BadHeader));
+
+        let mut buf = Vec::new();
+        cpio_entry(&mut buf, "file", 0o100644, &[0; 64]);
+        buf.truncate(150);
+        assert!(parse(leak(buf)).err() == Some(Error::Truncated));
+    }
+}
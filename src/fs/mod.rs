@@ -0,0 +1,39 @@
+//! Virtual filesystem: all filesystems are reachable through
+//! a single namespace built from the mount table
+
+pub mod vnode;
+pub use vnode::{Vnode, VnodeKind, Stat, DirEntry};
+pub mod vfs;
+pub mod file;
+pub use file::{File, FileRef, FdTable, OpenFlags, Whence};
+
+pub mod initrd;
+pub mod devfs;
+
+use alloc::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Error {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    ReadOnly,
+    /// Too many symlinks
+    Loop,
+    NameTooLong,
+    InvalidArgument,
+    BadDescriptor,
+    TooManyFiles,
+    /// Mount point is already in use
+    Busy,
+}
+
+/// Mounts initrd as root and devfs at /dev
+pub fn init() {
+    if let Err(err) = vfs::mount("/", Arc::new(initrd::Dir::root())) {
+        panic!("Failed to mount root: {:?}", err);
+    }
+    if let Err(err) = vfs::mount("/dev", Arc::new(devfs::Root)) {
+        println!("Failed to mount devfs: {:?}", err);
+    }
+}
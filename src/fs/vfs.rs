@@ -0,0 +1,284 @@
+//! Mount table and path resolution
+
+use super::{Error, Vnode, VnodeKind};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Symlinks followed during a single lookup
+pub const MAX_SYMLINKS: usize = 8;
+pub const PATH_MAX: usize = 4096;
+
+struct Mount {
+    /// Canonical absolute path
+    path:   String,
+    root:   Arc<dyn Vnode>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+fn mounted_at(path: &str) -> Option<Arc<dyn Vnode>> {
+    MOUNTS.lock().iter().find(|m| m.path == path).map(|m| m.root.clone())
+}
+
+fn join(stack: &[(String, Arc<dyn Vnode>)]) -> String {
+    if stack.is_empty() {
+        return String::from("/");
+    }
+
+    let mut path = String::new();
+    for (name, _) in stack {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
+
+/// Resolves `path` relative to `cwd`, which has to be canonical.
+/// A trailing symlink is only followed if `follow` is set.
+/// Returns canonical path of the node along with the node itself
+pub fn lookup(cwd: &str, path: &str, follow: bool) -> Result<(String, Arc<dyn Vnode>), Error> {
+    if path.is_empty() {
+        return Err(Error::NotFound);
+    }
+    if path.len() > PATH_MAX {
+        return Err(Error::NameTooLong);
+    }
+
+    let root = mounted_at("/").ok_or(Error::NotFound)?;
+    let mut stack: Vec<(String, Arc<dyn Vnode>)> = Vec::new();
+    // Components still to be walked, in reverse order
+    let mut pending: Vec<String> = path.rsplit('/').map(String::from).collect();
+    if !path.starts_with('/') {
+        pending.extend(cwd.rsplit('/').map(String::from));
+    }
+    let mut links = 0;
+
+    while let Some(name) = pending.pop() {
+        match name.as_str() {
+            "" | "." => continue,
+            ".." => {
+                stack.pop();
+                continue;
+            },
+            _ => {}
+        }
+
+        let dir = stack.last().map(|(_, node)| node.clone()).unwrap_or_else(|| root.clone());
+        if dir.stat().kind != VnodeKind::Directory {
+            return Err(Error::NotDirectory);
+        }
+
+        let mut node_path = join(&stack);
+        if !stack.is_empty() {
+            node_path.push('/');
+        }
+        node_path.push_str(&name);
+
+        // Mount points hide whatever is at their path
+        let node = match mounted_at(&node_path) {
+            Some(node) => node,
+            None => dir.lookup(&name)?
+        };
+
+        let is_last = pending.iter().all(|c| c.is_empty() || c == ".");
+        if node.stat().kind == VnodeKind::Symlink && (follow || !is_last) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(Error::Loop);
+            }
+
+            let target = node.readlink()?;
+            if target.starts_with('/') {
+                stack.clear();
+            }
+            pending.extend(target.rsplit('/').map(String::from));
+            continue;
+        }
+
+        stack.push((name, node));
+    }
+
+    let node = stack.last().map(|(_, node)| node.clone()).unwrap_or(root);
+    Ok((join(&stack), node))
+}
+
+/// Attaches filesystem `root` at `path`. The path must either be
+/// a directory or not exist at all, in which case it only appears
+/// in lookups
+pub fn mount(path: &str, root: Arc<dyn Vnode>) -> Result<(), Error> {
+    let path = path.trim_end_matches('/');
+    let canonical = if path.is_empty() {
+        String::from("/")
+    } else {
+        let (parent, name) = path.split_at(path.rfind('/').map(|i| i + 1).unwrap_or(0));
+        if name == "." || name == ".." {
+            return Err(Error::InvalidArgument);
+        }
+
+        let (mut canonical, dir) = lookup("/", if parent.is_empty() { "." } else { parent }, true)?;
+        match dir.lookup(name) {
+            Ok(node) if node.stat().kind != VnodeKind::Directory => return Err(Error::NotDirectory),
+            Ok(_) | Err(Error::NotFound) => {},
+            Err(err) => return Err(err)
+        }
+
+        if canonical != "/" {
+            canonical.push('/');
+        }
+        canonical.push_str(name);
+        canonical
+    };
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == canonical) {
+        return Err(Error::Busy);
+    }
+
+    println!("Mounted filesystem at {}", canonical);
+    mounts.push(Mount {
+        path: canonical,
+        root,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lookup, mount, Error, Vnode, VnodeKind, PATH_MAX};
+    use crate::fs::Stat;
+    use alloc::string::String;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use std::vec::Vec;
+
+    /// In-memory node, files' size tells them apart
+    struct Node {
+        kind:       VnodeKind,
+        size:       usize,
+        link:       &'static str,
+        children:   Vec<(&'static str, Arc<dyn Vnode>)>,
+    }
+
+    impl Vnode for Node {
+        fn stat(&self) -> Stat {
+            Stat { kind: self.kind, mode: 0o755, uid: 0, gid: 0, size: self.size, mtime: 0 }
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Vnode>, Error> {
+            if self.kind != VnodeKind::Directory {
+                return Err(Error::NotDirectory);
+            }
+            self.children.iter().find(|(n, _)| *n == name).map(|(_, node)| node.clone())
+                .ok_or(Error::NotFound)
+        }
+
+        fn readlink(&self) -> Result<String, Error> {
+            if self.kind != VnodeKind::Symlink {
+                return Err(Error::InvalidArgument);
+            }
+            Ok(String::from(self.link))
+        }
+    }
+
+    fn dir(children: Vec<(&'static str, Arc<dyn Vnode>)>) -> Arc<dyn Vnode> {
+        Arc::new(Node { kind: VnodeKind::Directory, size: 0, link: "", children })
+    }
+
+    fn file(size: usize) -> Arc<dyn Vnode> {
+        Arc::new(Node { kind: VnodeKind::Regular, size, link: "", children: Vec::new() })
+    }
+
+    fn symlink(link: &'static str) -> Arc<dyn Vnode> {
+        Arc::new(Node { kind: VnodeKind::Symlink, size: 0, link, children: Vec::new() })
+    }
+
+    /// Mounts the same tree for every test, whichever comes first
+    fn setup() {
+        let mut root = vec![
+            ("bin", dir(vec![("sh", file(1))])),
+            ("etc", dir(vec![("passwd", file(2))])),
+            // Hidden by the mount
+            ("mnt", dir(vec![("hidden", file(3))])),
+            ("sh", symlink("bin/sh")),
+            ("abs", symlink("/etc")),
+            ("loop", symlink("loop")),
+            ("l0", symlink("l1")),
+        ];
+        // l1 .. l8 is the longest chain allowed
+        let chain = [("l1", "l2"), ("l2", "l3"), ("l3", "l4"), ("l4", "l5"),
+                     ("l5", "l6"), ("l6", "l7"), ("l7", "l8"), ("l8", "/etc/passwd")];
+        for &(name, target) in chain.iter() {
+            root.push((name, symlink(target)));
+        }
+        let mnt = dir(vec![
+            ("data", file(4)),
+            ("up", symlink("../etc")),
+        ]);
+
+        for (path, node) in vec![("/", dir(root)), ("/mnt", mnt)] {
+            match mount(path, node) {
+                Ok(()) | Err(Error::Busy) => {},
+                Err(err) => panic!("Failed to mount {}: {:?}", path, err)
+            }
+        }
+    }
+
+    fn resolve(cwd: &str, path: &str, follow: bool) -> Result<(String, usize), Error> {
+        lookup(cwd, path, follow).map(|(path, node)| (path, node.stat().size))
+    }
+
+    #[test]
+    fn relative() {
+        setup();
+        assert_eq!(resolve("/", "/bin/sh", true), Ok((String::from("/bin/sh"), 1)));
+        assert_eq!(resolve("/bin", "sh", true), Ok((String::from("/bin/sh"), 1)));
+        assert_eq!(resolve("/bin", "./../etc//passwd", true), Ok((String::from("/etc/passwd"), 2)));
+        assert_eq!(resolve("/", "../..", true), Ok((String::from("/"), 0)));
+    }
+
+    #[test]
+    fn mount_points() {
+        setup();
+        assert_eq!(resolve("/", "/mnt/data", true), Ok((String::from("/mnt/data"), 4)));
+        assert_eq!(resolve("/", "/mnt/hidden", true), Err(Error::NotFound));
+        // `..` leaves the mounted filesystem
+        assert_eq!(resolve("/mnt", "..", true), Ok((String::from("/"), 0)));
+        assert_eq!(resolve("/mnt", "../etc/passwd", true), Ok((String::from("/etc/passwd"), 2)));
+        assert_eq!(resolve("/", "/mnt/../bin/sh", true), Ok((String::from("/bin/sh"), 1)));
+    }
+
+    #[test]
+    fn symlinks() {
+        setup();
+        assert_eq!(resolve("/", "/sh", true), Ok((String::from("/bin/sh"), 1)));
+        let (path, node) = lookup("/", "/sh", false).unwrap();
+        assert_eq!(path, "/sh");
+        assert_eq!(node.stat().kind, VnodeKind::Symlink);
+        // Links in the middle are followed regardless
+        assert_eq!(resolve("/", "/abs/passwd", false), Ok((String::from("/etc/passwd"), 2)));
+        // Relative link out of a mounted filesystem
+        assert_eq!(resolve("/", "/mnt/up/passwd", true), Ok((String::from("/etc/passwd"), 2)));
+    }
+
+    #[test]
+    fn symlink_limit() {
+        setup();
+        assert_eq!(resolve("/", "/loop", true), Err(Error::Loop));
+        assert_eq!(resolve("/", "/l1", true), Ok((String::from("/etc/passwd"), 2)));
+        assert_eq!(resolve("/", "/l0", true), Err(Error::Loop));
+        assert!(resolve("/", "/l0", false).is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        setup();
+        assert_eq!(resolve("/", "", true), Err(Error::NotFound));
+        assert_eq!(resolve("/", "/nope", true), Err(Error::NotFound));
+        assert_eq!(resolve("/", "/bin/sh/x", true), Err(Error::NotDirectory));
+
+        let long: String = core::iter::repeat('a').take(PATH_MAX + 1).collect();
+        assert_eq!(resolve("/", &long, true), Err(Error::NameTooLong));
+    }
+}
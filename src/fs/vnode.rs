@@ -0,0 +1,57 @@
+use super::Error;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VnodeKind {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub kind:   VnodeKind,
+    /// Permission bits
+    pub mode:   u32,
+    pub uid:    u32,
+    pub gid:    u32,
+    pub size:   usize,
+    pub mtime:  u64,
+}
+
+pub struct DirEntry {
+    pub name:   String,
+    pub kind:   VnodeKind,
+}
+
+/// A file, directory or device in some filesystem. Operations
+/// a node doesn't support fail with a suitable error
+pub trait Vnode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    /// Finds an entry `name` in this directory. `name` is
+    /// never empty, "." or ".."
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Vnode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Returns `index`-th entry of the directory or `None`
+    /// past the last one
+    fn readdir(&self, _index: usize) -> Result<Option<DirEntry>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    fn readlink(&self) -> Result<String, Error> {
+        Err(Error::InvalidArgument)
+    }
+}
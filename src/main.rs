@@ -14,9 +14,14 @@ extern crate yboot2_proto;
 pub const KERNEL_OFFSET: usize = 0xFFFFFF0000000000;
 static mut FB: usize = 0;
 
+/// Physical memory accessible through `virtualize`. Loader only
+/// maps the first 4GiB, the rest becomes available once the kernel
+/// switches to its own address space
+pub(crate) static mut PHYS_LIMIT: usize = 0x100000000;
+
 #[inline(always)]
 pub fn virtualize(phys: usize) -> usize {
-    assert!(phys < 0x100000000); // Because above 4GiB isn't mapped yet
+    assert!(phys < unsafe { PHYS_LIMIT });
     phys + KERNEL_OFFSET
 }
 
@@ -25,14 +30,17 @@ pub mod debug;
 pub mod arch;
 mod boot;
 pub mod dev;
+pub mod elf;
+pub mod fs;
 pub mod mem;
 pub mod sync;
 pub mod thread;
 pub mod syscall;
+pub mod time;
 
 fn task1(_: usize) {
     loop {
-        unsafe { llvm_asm!("syscall"::"{rax}"(1)); }
+        unsafe { llvm_asm!("hlt"); }
     }
 }
 
@@ -56,6 +64,8 @@ fn task2(_: usize) {
 }
 
 use thread::{Process, Thread};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 
 #[no_mangle]
 pub extern "C" fn kernel_main() {
@@ -68,21 +78,61 @@ pub extern "C" fn kernel_main() {
     unsafe { FB = virtualize(boot.video.framebuffer as usize); }
 
     arch::x86::gdt::init();
+    arch::x86::cpu::init_bsp();
     arch::x86::idt::init();
+    arch::x86::usercopy::init();
 
-    mem::phys::init(&boot.memory_map);
+    mem::init();
+    let initrd = (boot.initrd_base as usize, (boot.initrd_base + boot.initrd_size) as usize);
+    mem::phys::init(&boot.memory_map, initrd);
+    mem::kernel::init(&boot.memory_map);
     mem::heap::init_somewhere(1024 * 1024 * 4);
 
+    let rsdp = if boot.rsdp != 0 { Some(boot.rsdp as usize) } else { None };
+    let acpi = dev::x86::acpi::init(rsdp);
     // Initialize local APIC
-    dev::x86::apic::init(virtualize(0xFEE00000));
-    dev::x86::acpi::init(Some(boot.rsdp as usize));
+    dev::x86::apic::init(virtualize(dev::x86::acpi::local_apic_address()));
+    arch::x86::smp::init_bsp();
+    if acpi {
+        dev::x86::acpi::init_nmi_sources();
+        dev::x86::acpi::init_local_nmi();
+    } else {
+        println!("No ACPI, using PIC and PIT on a single CPU");
+    }
+    dev::x86::pic::init(!acpi);
     dev::x86::ps2::init();
+    time::init();
+    dev::x86::rtc::init();
 
     syscall::init();
 
-    let mut proc = Process::new_kernel();
+    if boot.initrd_size != 0 {
+        fs::initrd::init(virtualize(boot.initrd_base as usize), boot.initrd_size as usize);
+    }
+    fs::init();
+
+    // Threads point to their owner, which has to outlive
+    // this stack frame
+    let proc = Box::leak(Box::new(Process::new_kernel()));
     proc.spawn(task1 as usize, 0).unwrap();
-    proc.spawn(task2 as usize, 0).unwrap();
+    let compute = proc.spawn(task2 as usize, 0).unwrap();
+    // Busy loop, shouldn't get in the way of anything else
+    unsafe { (*compute).set_nice(thread::MAX_NICE); }
+
+    if let Some(init) = fs::initrd::lookup("/init") {
+        if let Err(err) = elf::load(Arc::new(init.data()), &["/init"], &[]) {
+            println!("Failed to start /init: {:?}", err);
+        }
+    }
+    unsafe {
+        mem::kernel::leave_loader_stack(enter_threads);
+    }
+}
+
+extern "C" fn enter_threads() -> ! {
+    mem::kernel::remove_alias();
+    // The alias covers the AP trampoline with NX pages
+    arch::x86::smp::init();
     // Enter the thread
     unsafe {
         thread::enter();
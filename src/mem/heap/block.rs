@@ -1,9 +1,12 @@
 use core::convert::TryInto;
-use core::mem::size_of;
+use core::mem::{align_of, size_of};
 use core::ptr::null_mut;
 
 pub const MAGIC: u32 = 0xFEDFAD80;
 pub const USED: u32 = 1;
+/// Sizes are rounded up to this, so that headers
+/// and data are always aligned to it
+pub const ALIGN: usize = align_of::<Block>();
 
 pub struct Block {
     pub magic: u32,
@@ -88,14 +91,42 @@ impl Block {
         false
     }
 
-    /// Allocate requested size from self, possibly
-    /// splitting it to create a new one
+    /// Allocate requested size aligned to `align` (a power
+    /// of two) from self, possibly splitting it to create new
+    /// blocks before and after the data
     /// `unsafe` because of special requirements
-    pub unsafe fn alloc(&mut self, count: usize) -> Option<*mut u8> {
-        if self.is_used() || self.size() < count {
+    pub unsafe fn alloc(&mut self, count: usize, align: usize) -> Option<*mut u8> {
+        if self.is_used() {
+            return None;
+        }
+        let count = count.checked_add(ALIGN - 1)? & !(ALIGN - 1);
+        let align = if align > ALIGN { align } else { ALIGN };
+
+        let data = self.data() as usize;
+        let end = data + self.size();
+        let mut aligned = data.checked_add(align - 1)? & !(align - 1);
+        // Leading space has to fit a header
+        if aligned != data && aligned - data < size_of::<Block>() {
+            aligned += align;
+        }
+        if aligned.checked_add(count)? > end {
             return None;
         }
 
+        if aligned == data {
+            self.alloc_here(count)
+        } else {
+            // Leading space stays free as `self`
+            let block = Block::place(aligned - size_of::<Block>(), end - aligned);
+            self.size = (aligned - size_of::<Block>() - data) as u32;
+            self.insert(block);
+            block.alloc_here(count)
+        }
+    }
+
+    /// Marks the block used, splitting whatever remains
+    /// after `count` bytes into a new one
+    unsafe fn alloc_here(&mut self, count: usize) -> Option<*mut u8> {
         if self.size() >= count + size_of::<Block>() {
             let new_block_addr = self.data() as usize + count;
             let new_block = Block::place(new_block_addr, self.size() - count - size_of::<Block>());
@@ -107,6 +138,16 @@ impl Block {
         self.magic |= USED;
         Some(self.data())
     }
+
+    /// Marks the block as unused and merges it with
+    /// unused neighbours
+    pub fn free(&mut self) {
+        self.magic &= !USED;
+        self.merge();
+        if self.prev != null_mut() {
+            unsafe { &mut *self.prev }.merge();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -228,7 +269,7 @@ mod test {
         let buf_ptr = buf.as_mut().as_ptr() as usize;
 
         let b0 = unsafe { Block::place(buf_ptr, 32) };
-        let res = unsafe { b0.alloc(24) };
+        let res = unsafe { b0.alloc(24, 1) };
 
         assert!(res.is_some());
         assert!(res.unwrap() == unsafe { b0.data() });
@@ -243,7 +284,7 @@ mod test {
         let buf_ptr = buf.as_mut().as_ptr() as usize;
 
         let b0 = unsafe { Block::place(buf_ptr, 128) };
-        let res = unsafe { b0.alloc(64) };
+        let res = unsafe { b0.alloc(64, 1) };
 
         assert!(res.is_some());
         assert!(res.unwrap() == unsafe { b0.data() });
@@ -265,9 +306,84 @@ mod test {
         let buf_ptr = buf.as_mut().as_ptr() as usize;
 
         let b0 = unsafe { Block::place(buf_ptr, 128) };
-        assert!(unsafe { b0.alloc(65536) }.is_none());
+        assert!(unsafe { b0.alloc(65536, 1) }.is_none());
         assert!(b0.is_valid());
         assert!(!b0.is_used());
         assert!(b0.size == 128);
     }
+
+    #[test]
+    fn free_merge() {
+        let mut buf = Box::new([0u8; 32768]);
+        let buf_ptr = buf.as_mut().as_ptr() as usize;
+
+        let b0 = unsafe { Block::place(buf_ptr, 256) };
+        unsafe { b0.alloc(64, 1) };
+        let b1 = unsafe { &mut *b0.next };
+        unsafe { b1.alloc(64, 1) };
+
+        b0.free();
+        assert!(!b0.is_used());
+        assert!(b0.next == b1 as *mut Block);
+        assert!(b0.size == 64);
+
+        // Merges with both unused neighbours
+        b1.free();
+        assert!(b0.is_valid());
+        assert!(!b0.is_used());
+        assert!(b0.next == null_mut());
+        assert!(b0.size() == 256);
+    }
+
+    #[test]
+    fn alloc_round_up() {
+        let mut buf = Box::new([0u8; 32768]);
+        let buf_ptr = buf.as_mut().as_ptr() as usize;
+
+        let b0 = unsafe { Block::place(buf_ptr, 256) };
+        let res = unsafe { b0.alloc(13, 1) };
+
+        assert!(res.is_some());
+        assert!(b0.size() == 16);
+        let b1 = unsafe { &mut *b0.next };
+        assert!(b1 as *mut Block as usize % ALIGN == 0);
+        assert!(b1.size() == 256 - 16 - size_of::<Block>());
+    }
+
+    #[test]
+    fn alloc_aligned() {
+        let mut buf = Box::new([0u8; 32768]);
+        let buf_ptr = (buf.as_mut().as_ptr() as usize + 4095) & !4095;
+
+        let b0 = unsafe { Block::place(buf_ptr, 16384) };
+        let res = unsafe { b0.alloc(100, 4096) }.unwrap();
+
+        assert!(res as usize % 4096 == 0);
+        assert!(res as usize == buf_ptr + 4096);
+        // Leading space stays free
+        assert!(!b0.is_used());
+        assert!(b0.size() == 4096 - 2 * size_of::<Block>());
+        let b1 = unsafe { &mut *b0.next };
+        assert!(b1.is_used());
+        assert!(b1.prev == b0 as *mut Block);
+        assert!(unsafe { b1.data() } == res);
+        assert!(b1.size() == 104);
+
+        // Everything merges back
+        b1.free();
+        assert!(b0.next == null_mut());
+        assert!(b0.size() == 16384);
+    }
+
+    #[test]
+    fn no_alloc_aligned() {
+        let mut buf = Box::new([0u8; 32768]);
+        let buf_ptr = (buf.as_mut().as_ptr() as usize + 4095) & !4095;
+
+        // Block ends before the next page boundary
+        let b0 = unsafe { Block::place(buf_ptr, 4096 - 2 * size_of::<Block>()) };
+        assert!(unsafe { b0.alloc(16, 4096) }.is_none());
+        assert!(b0.next == null_mut());
+        assert!(!b0.is_used());
+    }
 }
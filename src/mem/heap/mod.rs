@@ -1,7 +1,8 @@
 use crate::mem::phys::{self, PageUsage};
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::RefCell;
 use crate::virtualize;
+use crate::sync::IrqDisable;
+use spin::Mutex;
 
 pub mod zone;
 pub mod block;
@@ -13,7 +14,7 @@ pub use block::Block;
 const HEAP_ZONES: usize = 4;
 
 struct KernelHeap {
-    zones: [RefCell<Zone>; HEAP_ZONES],
+    zones: [Mutex<Zone>; HEAP_ZONES],
 }
 
 impl KernelHeap {}
@@ -21,24 +22,31 @@ impl KernelHeap {}
 unsafe impl GlobalAlloc for KernelHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let size = layout.size();
-        // TODO: align
-        let _align = layout.align();
+        let align = layout.align();
 
-        println!("alloc({})", size);
-        for cell in self.zones.iter() {
-            if let Some(ptr) = cell.borrow_mut().alloc(size) {
+        let _irq = IrqDisable::new();
+        for zone in self.zones.iter() {
+            if let Some(ptr) = zone.lock().alloc(size, align) {
                 return ptr;
             }
         }
         core::ptr::null_mut()
     }
-    // TODO: free()
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let _irq = IrqDisable::new();
+        for zone in self.zones.iter() {
+            if zone.lock().free(ptr) {
+                return;
+            }
+        }
+        panic!("Freeing a pointer not from the heap: {:p}", ptr);
+    }
 }
 
 #[global_allocator]
 static mut HEAP: KernelHeap = KernelHeap {
-    zones: [RefCell::new(Zone::empty()); HEAP_ZONES],
+    zones: [Mutex::new(Zone::empty()); HEAP_ZONES],
 };
 
 #[alloc_error_handler]
@@ -52,8 +60,8 @@ pub unsafe fn init(at: usize, size: usize) {
         panic!("Zone size is not page-aligned");
     }
 
-    for (i, cell) in HEAP.zones.iter().enumerate() {
-        *cell.borrow_mut() = Zone::place(at + zone_size * i, zone_size);
+    for (i, zone) in HEAP.zones.iter().enumerate() {
+        *zone.lock() = Zone::place(at + zone_size * i, zone_size);
     }
 }
 
@@ -62,10 +70,9 @@ pub fn init_somewhere(zone_size: usize) {
         panic!("Zone size is not page-aligned");
     }
 
-    println!("Test");
-    for cell in unsafe { &HEAP }.zones.iter() {
+    for zone in unsafe { &HEAP }.zones.iter() {
         if let Some(phys_base) = phys::alloc_contiguous(PageUsage::Kernel, zone_size / 4096) {
-            *cell.borrow_mut() = unsafe { Zone::place(virtualize(phys_base), zone_size) };
+            *zone.lock() = unsafe { Zone::place(virtualize(phys_base), zone_size) };
         } else {
             panic!("Failed to allocate {} contiguous physical pages for heap zone", zone_size / 4096);
         }
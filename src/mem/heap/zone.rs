@@ -73,14 +73,27 @@ impl Zone {
         }
     }
 
-    pub unsafe fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+    pub unsafe fn alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
         for block in self.iter_mut() {
-            if let Some(data) = block.alloc(size) {
+            if let Some(data) = block.alloc(size, align) {
                 return Some(data);
             }
         }
         None
     }
+
+    /// Releases the block `ptr` was allocated from, returns
+    /// false if it doesn't belong to this zone
+    pub unsafe fn free(&mut self, ptr: *mut u8) -> bool {
+        for block in self.iter_mut() {
+            if block.data() == ptr {
+                assert!(block.is_used(), "Double free: {:p}", ptr);
+                block.free();
+                return true;
+            }
+        }
+        false
+    }
 }
 
 impl Stat {
@@ -148,7 +161,7 @@ mod test {
         let buf_ptr = buf.as_mut().as_ptr() as usize;
 
         let mut zone = unsafe { Zone::place(buf_ptr, 32768) };
-        let res = unsafe { zone.alloc(256) };
+        let res = unsafe { zone.alloc(256, 1) };
         let block = unsafe { &mut **zone.head.lock() };
         assert!(res.is_some());
         assert!(res.unwrap() == unsafe { block.data() });
@@ -178,9 +191,9 @@ mod test {
 
         let mut zone = unsafe { Zone::place(buf_ptr, 32768) };
         unsafe {
-            zone.alloc(16);
-            zone.alloc(24);
-            zone.alloc(32);
+            zone.alloc(16, 1);
+            zone.alloc(24, 1);
+            zone.alloc(32, 1);
         }
         let mut st0 = Stat::new();
 
@@ -190,4 +203,23 @@ mod test {
         assert!(st0.bytes_free == 32768 - 4 * size_of::<Block>() - 16 - 24 - 32);
         assert!(st0.bytes_alloc == 16 + 24 + 32);
     }
+
+    #[test]
+    fn free() {
+        let mut buf = Box::new([0u8; 32768]);
+        let buf_ptr = buf.as_mut().as_ptr() as usize;
+
+        let mut zone = unsafe { Zone::place(buf_ptr, 32768) };
+        let ptrs = unsafe { [zone.alloc(16, 1).unwrap(), zone.alloc(24, 1).unwrap(), zone.alloc(32, 1).unwrap()] };
+        assert!(!unsafe { zone.free(null_mut()) });
+        for &ptr in ptrs.iter() {
+            assert!(unsafe { zone.free(ptr) });
+        }
+        let mut st0 = Stat::new();
+
+        zone.stat(&mut st0);
+        assert!(st0.blocks == 1);
+        assert!(st0.alloc == 0);
+        assert!(st0.bytes_free == 32768 - size_of::<Block>());
+    }
 }
@@ -0,0 +1,199 @@
+//! Kernel address space setup: direct mapping of physical
+//! memory at `KERNEL_OFFSET` with kernel image sections
+//! mapped with their own access rights (W^X)
+
+use super::{Level, PageTable, PageFlags, Space, L3, L4, KERNEL, PAGE_SIZE, LARGE_PAGE_SIZE, ADDR_MASK};
+use super::phys::{self, PageUsage};
+use crate::arch::x86::{regs, intrinsics};
+use crate::{virtualize, KERNEL_OFFSET};
+use yboot2_proto::MemoryMapInfo;
+use core::cmp::max;
+
+pub const HUGE_PAGE_SIZE: usize = 0x40000000;
+
+/// Physical memory the loader maps for us. Always mapped
+/// as it contains legacy/MMIO regions not listed as RAM
+const LOW_MEMORY_END: usize = 0x100000000;
+/// Direct mapping must fit into a single PML4 entry
+const DIRECT_MAP_LIMIT: usize = 1 << 39;
+
+const DIRECT_FLAGS: PageFlags = PageFlags::from_bits_truncate(
+    PageFlags::WRITE.bits() | PageFlags::NX.bits() | PageFlags::GLOBAL.bits()
+);
+/// Not global, so that nothing of the alias outlives it in TLBs
+const ALIAS_FLAGS: PageFlags = PageFlags::from_bits_truncate(
+    PageFlags::WRITE.bits() | PageFlags::NX.bits()
+);
+
+const BOOT_STACK_PAGES: usize = 4;
+
+extern "C" {
+    static _kernel_start: u8;
+    static _kernel_rodata_start: u8;
+    static _kernel_data_start: u8;
+    static _kernel_end: u8;
+}
+
+/// Physical layout of kernel image sections
+struct Image {
+    text:   usize,
+    rodata: usize,
+    data:   usize,
+    end:    usize,
+}
+
+impl Image {
+    fn get() -> Image {
+        let phys = |sym: &u8| sym as *const _ as usize - KERNEL_OFFSET;
+        unsafe {
+            Image {
+                text:   phys(&_kernel_start),
+                rodata: phys(&_kernel_rodata_start),
+                data:   phys(&_kernel_data_start),
+                end:    (phys(&_kernel_end) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            }
+        }
+    }
+
+    fn page_flags(&self, phys: usize) -> PageFlags {
+        if phys >= self.text && phys < self.rodata {
+            PageFlags::GLOBAL
+        } else if phys >= self.rodata && phys < self.data {
+            PageFlags::GLOBAL | PageFlags::NX
+        } else {
+            // .data, .bss and non-kernel pages
+            DIRECT_FLAGS
+        }
+    }
+
+    /// Returns `true` if a page of `size` at `phys` can be
+    /// used to map `phys .. end`
+    fn fits(&self, phys: usize, end: usize, size: usize) -> bool {
+        phys & (size - 1) == 0 &&
+        phys + size <= end &&
+        (phys + size <= self.text || phys >= self.end)
+    }
+}
+
+fn has_huge_pages() -> bool {
+    let (_, _, _, edx) = intrinsics::cpuid(0x80000001);
+    edx & (1 << 26) != 0
+}
+
+fn map_huge(space: &mut Space, virt: usize, phys: usize) {
+    let pdpt = space.next_or_alloc(virt).unwrap();
+    pdpt.entries[PageTable::<L3>::index(virt)] = phys as u64 |
+        (DIRECT_FLAGS | PageFlags::PRESENT | PageFlags::HUGE).bits();
+}
+
+/// Identity maps low memory in the lower half, with its own
+/// tables so that it can be torn down separately
+fn map_alias(space: &mut Space, huge: bool) {
+    let mut phys = 0;
+    while phys < LOW_MEMORY_END {
+        if huge {
+            let pdpt = space.next_or_alloc(phys).unwrap();
+            pdpt.entries[PageTable::<L3>::index(phys)] = phys as u64 |
+                (ALIAS_FLAGS | PageFlags::PRESENT | PageFlags::HUGE).bits();
+            phys += HUGE_PAGE_SIZE;
+        } else {
+            space.map_large(phys, phys, ALIAS_FLAGS).unwrap();
+            phys += LARGE_PAGE_SIZE;
+        }
+    }
+}
+
+/// Removes the lower half alias of low memory, if any. Nothing
+/// may run on the loader stack anymore
+pub fn remove_alias() {
+    let space = unsafe { KERNEL.as_mut() }.unwrap();
+    let entry = space.entries[0];
+    if entry & PageFlags::PRESENT.bits() == 0 {
+        return;
+    }
+    space.entries[0] = 0;
+
+    // Only the tables belong to the alias, not the pages
+    let pdpt_phys = (entry & ADDR_MASK) as usize;
+    let pdpt = unsafe { &*(virtualize(pdpt_phys) as *const PageTable<L3>) };
+    for &entry in pdpt.entries.iter() {
+        if entry & PageFlags::PRESENT.bits() != 0 && entry & PageFlags::HUGE.bits() == 0 {
+            phys::free_page((entry & ADDR_MASK) as usize);
+        }
+    }
+    phys::free_page(pdpt_phys);
+
+    unsafe { regs::cr3::write(regs::cr3::read()); }
+}
+
+/// Switches to a stack in the upper half and calls `entry`,
+/// which should `remove_alias` if the loader stack was aliased
+pub unsafe fn leave_loader_stack(entry: extern "C" fn() -> !) -> ! {
+    let stack = virtualize(phys::alloc_contiguous(PageUsage::Kernel, BOOT_STACK_PAGES).unwrap());
+    let top = stack + BOOT_STACK_PAGES * PAGE_SIZE;
+    llvm_asm!("mov $0, %rsp
+               call *$1"::"r"(top), "r"(entry):"memory":"volatile");
+    unreachable!();
+}
+
+/// Maps physical `start .. end` to `KERNEL_OFFSET + start`
+/// using the largest pages possible
+fn map_range(space: &mut Space, image: &Image, huge: bool, start: usize, end: usize) {
+    let mut phys = start & !(PAGE_SIZE - 1);
+    let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    while phys < end {
+        let virt = phys + KERNEL_OFFSET;
+
+        if huge && image.fits(phys, end, HUGE_PAGE_SIZE) {
+            map_huge(space, virt, phys);
+            phys += HUGE_PAGE_SIZE;
+        } else if image.fits(phys, end, LARGE_PAGE_SIZE) {
+            space.map_large(virt, phys, DIRECT_FLAGS).unwrap();
+            phys += LARGE_PAGE_SIZE;
+        } else {
+            space.map(virt, phys, image.page_flags(phys)).unwrap();
+            phys += PAGE_SIZE;
+        }
+    }
+}
+
+/// Builds kernel address space and switches to it
+pub fn init(mmap: &MemoryMapInfo) {
+    let image = Image::get();
+    let huge = has_huge_pages();
+    let (space_phys, space) = PageTable::<L4>::alloc().unwrap();
+
+    map_range(space, &image, huge, 0, LOW_MEMORY_END);
+    let mut limit = LOW_MEMORY_END;
+    for item in mmap.iter(true) {
+        if item.end() > LOW_MEMORY_END {
+            map_range(space, &image, huge, max(item.begin(), LOW_MEMORY_END), item.end());
+            limit = max(limit, item.end());
+        }
+    }
+    assert!(limit <= DIRECT_MAP_LIMIT, "Physical memory does not fit into direct mapping");
+
+    // Upper half tables are shared with user spaces, so all of
+    // them have to exist before any user space is created
+    for index in 256 .. 512 {
+        space.next_or_alloc(index << L4::INDEX_SHIFT).unwrap();
+    }
+
+    // Loader may have left us on a stack in the lower half,
+    // keep it accessible until `leave_loader_stack`
+    let rsp: usize;
+    unsafe { llvm_asm!("mov %rsp, $0":"=r"(rsp)); }
+    if rsp < KERNEL_OFFSET {
+        map_alias(space, huge);
+    }
+
+    println!("Switching to kernel address space: {}MiB mapped", limit >> 20);
+    unsafe {
+        regs::cr3::write(space_phys);
+        crate::PHYS_LIMIT = limit;
+        KERNEL = Some(space);
+    }
+
+    super::phys::init_late(mmap);
+}
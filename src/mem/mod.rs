@@ -1,25 +1,80 @@
 //! Virtual memory table management stuff
 
 use core::marker::PhantomData;
-use crate::virtualize;
+use bitflags::bitflags;
+use crate::{virtualize, KERNEL_OFFSET};
+use crate::arch::x86::{regs, intrinsics};
+use phys::PageUsage;
 
 pub mod phys;
 pub mod heap;
+pub mod kernel;
+pub mod region;
+pub mod user;
 
-/// Page table entry is valid
-pub const PAGE_PRESENT: u64     = 1 << 0;
-/// Depending on translation level may mean
-/// alternate page size (only 2MiB pages
-/// are used so far)
-pub const PAGE_HUGE: u64        = 1 << 7;
+bitflags! {
+    /// Page table entry flags
+    pub struct PageFlags: u64 {
+        /// Page table entry is valid
+        const PRESENT       = 1 << 0;
+        const WRITE         = 1 << 1;
+        /// Page is accessible from ring 3
+        const USER          = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE      = 1 << 4;
+        const ACCESSED      = 1 << 5;
+        const DIRTY         = 1 << 6;
+        /// Depending on translation level may mean
+        /// alternate page size (2MiB at L2, 1GiB at L3)
+        const HUGE          = 1 << 7;
+        /// TLB entry survives CR3 reloads
+        const GLOBAL        = 1 << 8;
+        /// Software-defined: page is shared copy-on-write
+        const COW           = 1 << 9;
+        /// Instruction fetches are not allowed
+        const NX            = 1 << 63;
+    }
+}
+
+/// Physical address bits of a page table entry
+const ADDR_MASK: u64 = 0x000FFFFFFFFFF000;
+
+pub const PAGE_SIZE: usize = 0x1000;
+pub const LARGE_PAGE_SIZE: usize = 0x200000;
+/// End of the lower (user) half of address space
+pub const USER_END: usize = 0x0000800000000000;
+
+#[derive(Debug, PartialEq)]
+pub enum MapError {
+    /// Address is not aligned to the page size
+    Misaligned,
+    /// Requested page is already mapped
+    AlreadyMapped,
+    /// Nothing is mapped at the address
+    NotMapped,
+    /// Address is covered by a larger page than requested
+    LargePage,
+    /// Could not allocate a translation table
+    OutOfMemory,
+}
 
 /// A single specific page table nesting level
 pub trait Level {
     const INDEX_SHIFT: usize;
 }
+/// Level which points to another table level
+pub trait NextLevel: Level {
+    type Next: Level;
+}
+/// Teardown of translation tables
+trait Release {
+    /// Frees all next level tables and drops references
+    /// to the pages mapped through them
+    unsafe fn release(&mut self);
+}
 /// Address translation logic
 pub trait Table {
-    fn translate(&self, virt: usize, flags: Option<&mut u64>) -> Option<usize>;
+    fn translate(&self, virt: usize, flags: Option<&mut PageFlags>) -> Option<usize>;
 }
 
 /// A wrapper for [u64; 512] page table providing
@@ -40,7 +95,7 @@ pub struct L3;
 pub struct L4;
 
 /// Root level of page tables, covers the whole memory space
-type Space = PageTable<L4>;
+pub type Space = PageTable<L4>;
 
 impl Level for L1 {
     const INDEX_SHIFT: usize = 12;
@@ -55,44 +110,316 @@ impl Level for L4 {
     const INDEX_SHIFT: usize = 39;
 }
 
+impl NextLevel for L2 {
+    type Next = L1;
+}
+impl NextLevel for L3 {
+    type Next = L2;
+}
+impl NextLevel for L4 {
+    type Next = L3;
+}
+
 impl<T: Level> Table for PageTable<T> {
-    fn translate(&self, virt: usize, flags: Option<&mut u64>) -> Option<usize> {
-        let index = (virt >> T::INDEX_SHIFT) & 0x1FF;
-        let entry = self.entries[index];
-
-        if entry & PAGE_PRESENT != 0 {
-            if flags.is_some() {
-                // TODO: NX
-                *flags.unwrap() = entry & 0xFFF;
+    fn translate(&self, virt: usize, flags: Option<&mut PageFlags>) -> Option<usize> {
+        let entry = self.entries[Self::index(virt)];
+
+        if entry & PageFlags::PRESENT.bits() != 0 {
+            if let Some(flags) = flags {
+                *flags = PageFlags::from_bits_truncate(entry & !ADDR_MASK);
             }
 
-            Some((entry & !0xFFF) as usize)
+            Some((entry & ADDR_MASK) as usize)
         } else {
             None
         }
     }
 }
 
+impl<T: Level> PageTable<T> {
+    #[inline(always)]
+    fn index(virt: usize) -> usize {
+        (virt >> T::INDEX_SHIFT) & 0x1FF
+    }
+
+    /// Allocates a new zeroed table
+    fn alloc() -> Result<(usize, &'static mut PageTable<T>), MapError> {
+        let phys = phys::alloc_page(PageUsage::Paging).ok_or(MapError::OutOfMemory)?;
+        let table = unsafe { &mut *(virtualize(phys) as *mut PageTable<T>) };
+        table.entries = [0; 512];
+        Ok((phys, table))
+    }
+}
+
+impl<T: NextLevel> PageTable<T> {
+    /// Returns next level table covering `virt`
+    fn next_mut(&mut self, virt: usize) -> Result<&'static mut PageTable<T::Next>, MapError> {
+        let entry = self.entries[Self::index(virt)];
+
+        if entry & PageFlags::PRESENT.bits() == 0 {
+            Err(MapError::NotMapped)
+        } else if entry & PageFlags::HUGE.bits() != 0 {
+            Err(MapError::LargePage)
+        } else {
+            Ok(unsafe { &mut *(virtualize((entry & ADDR_MASK) as usize) as *mut _) })
+        }
+    }
+
+    /// Same as `next_mut`, but allocates the table if it's missing
+    fn next_or_alloc(&mut self, virt: usize) -> Result<&'static mut PageTable<T::Next>, MapError> {
+        match self.next_mut(virt) {
+            Err(MapError::NotMapped) => {
+                let (phys, table) = PageTable::<T::Next>::alloc()?;
+                // Access rights are only enforced at the last level
+                self.entries[Self::index(virt)] = phys as u64 |
+                    (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits();
+                Ok(table)
+            },
+            res => res
+        }
+    }
+}
+
+impl Release for PageTable<L1> {
+    unsafe fn release(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if *entry & PageFlags::PRESENT.bits() != 0 {
+                phys::put((*entry & ADDR_MASK) as usize);
+            }
+            *entry = 0;
+        }
+    }
+}
+
+impl<T: NextLevel> Release for PageTable<T> where PageTable<T::Next>: Release {
+    unsafe fn release(&mut self) {
+        for index in 0 .. 512 {
+            let entry = self.entries[index];
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                continue;
+            }
+
+            let addr = (entry & ADDR_MASK) as usize;
+            if entry & PageFlags::HUGE.bits() != 0 {
+                for page in 0 .. 1 << (T::INDEX_SHIFT - 12) {
+                    phys::put(addr + page * PAGE_SIZE);
+                }
+            } else {
+                let table = &mut *(virtualize(addr) as *mut PageTable<T::Next>);
+                table.release();
+                phys::free_page(addr);
+            }
+            self.entries[index] = 0;
+        }
+    }
+}
+
+impl PageTable<L4> {
+    /// Creates a new address space sharing kernel's upper half
+    pub fn new_user() -> Result<&'static mut Space, MapError> {
+        let (_, space) = Self::alloc()?;
+        let kernel = unsafe { KERNEL.as_ref() }.unwrap();
+
+        space.entries[256 ..].copy_from_slice(&kernel.entries[256 ..]);
+        Ok(space)
+    }
+
+    /// Creates a copy of the user half: pages are shared between
+    /// both spaces, writable ones become read-only copy-on-write
+    pub fn fork(&mut self) -> Result<&'static mut Space, MapError> {
+        let child = Space::new_user()?;
+
+        if let Err(err) = self.fork_into(child) {
+            unsafe { child.destroy(); }
+            return Err(err);
+        }
+
+        // Parent's writable pages were just write-protected
+        if regs::cr3::read() == self.physical() {
+            unsafe { regs::cr3::write(self.physical()); }
+        }
+
+        Ok(child)
+    }
+
+    fn fork_into(&mut self, child: &mut Space) -> Result<(), MapError> {
+        for i4 in 0 .. 256 {
+            let virt4 = i4 << L4::INDEX_SHIFT;
+            let pdpt = match self.next_mut(virt4) {
+                Ok(table) => table,
+                Err(_) => continue
+            };
+
+            for i3 in 0 .. 512 {
+                let virt3 = virt4 | (i3 << L3::INDEX_SHIFT);
+                let pd = match pdpt.next_mut(virt3) {
+                    Ok(table) => table,
+                    Err(_) => continue
+                };
+
+                for i2 in 0 .. 512 {
+                    let virt2 = virt3 | (i2 << L2::INDEX_SHIFT);
+                    // Large pages are not used in user space
+                    let pt = match pd.next_mut(virt2) {
+                        Ok(table) => table,
+                        Err(_) => continue
+                    };
+
+                    for i1 in 0 .. 512 {
+                        let entry = &mut pt.entries[i1];
+                        if *entry & PageFlags::PRESENT.bits() == 0 {
+                            continue;
+                        }
+
+                        let virt = virt2 | (i1 << L1::INDEX_SHIFT);
+                        let phys = (*entry & ADDR_MASK) as usize;
+                        let mut flags = PageFlags::from_bits_truncate(*entry & !ADDR_MASK) &
+                            !(PageFlags::PRESENT | PageFlags::ACCESSED | PageFlags::DIRTY);
+
+                        if flags.contains(PageFlags::WRITE) {
+                            flags.remove(PageFlags::WRITE);
+                            flags.insert(PageFlags::COW);
+                            *entry = phys as u64 | (flags | PageFlags::PRESENT).bits();
+                        }
+
+                        phys::get(phys);
+                        if let Err(err) = child.map(virt, phys, flags) {
+                            phys::put(phys);
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Physical address of the table, suitable for loading into CR3
+    pub fn physical(&self) -> usize {
+        self as *const _ as usize - KERNEL_OFFSET
+    }
+
+    /// Frees lower half translation tables along with the PML4
+    /// itself and drops references to pages mapped in user space.
+    /// The space must not be active on any CPU
+    pub unsafe fn destroy(&mut self) {
+        for index in 0 .. 256 {
+            let entry = self.entries[index];
+            if entry & PageFlags::PRESENT.bits() != 0 {
+                let addr = (entry & ADDR_MASK) as usize;
+                (&mut *(virtualize(addr) as *mut PageTable<L3>)).release();
+                phys::free_page(addr);
+            }
+        }
+
+        phys::free_page(self.physical());
+    }
+
+    /// Maps a single 4KiB page at `virt` to `phys`
+    pub fn map(&mut self, virt: usize, phys: usize, flags: PageFlags) -> Result<(), MapError> {
+        if virt & (PAGE_SIZE - 1) != 0 || phys & (PAGE_SIZE - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let pt = self.next_or_alloc(virt)?.next_or_alloc(virt)?.next_or_alloc(virt)?;
+        let entry = &mut pt.entries[PageTable::<L1>::index(virt)];
+        if *entry & PageFlags::PRESENT.bits() != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *entry = (phys as u64 & ADDR_MASK) | (flags | PageFlags::PRESENT).bits();
+        intrinsics::flush_tlb_entry(virt);
+        Ok(())
+    }
+
+    /// Maps a single 2MiB page at `virt` to `phys`
+    pub fn map_large(&mut self, virt: usize, phys: usize, flags: PageFlags) -> Result<(), MapError> {
+        if virt & (LARGE_PAGE_SIZE - 1) != 0 || phys & (LARGE_PAGE_SIZE - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let pd = self.next_or_alloc(virt)?.next_or_alloc(virt)?;
+        let entry = &mut pd.entries[PageTable::<L2>::index(virt)];
+        if *entry & PageFlags::PRESENT.bits() != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        *entry = (phys as u64 & ADDR_MASK) | (flags | PageFlags::PRESENT | PageFlags::HUGE).bits();
+        intrinsics::flush_tlb_entry(virt);
+        Ok(())
+    }
+
+    /// Removes a 4KiB or 2MiB page mapping, returning the
+    /// physical address it pointed to. Neither the page nor
+    /// translation tables are freed
+    pub fn unmap(&mut self, virt: usize) -> Result<usize, MapError> {
+        let entry = self.leaf_entry(virt)?;
+        let phys = (*entry & ADDR_MASK) as usize;
+
+        *entry = 0;
+        intrinsics::flush_tlb_entry(virt);
+        Ok(phys)
+    }
+
+    /// Changes access flags of an existing 4KiB or 2MiB page mapping
+    pub fn protect(&mut self, virt: usize, flags: PageFlags) -> Result<(), MapError> {
+        let entry = self.leaf_entry(virt)?;
+        let size = *entry & PageFlags::HUGE.bits();
+
+        *entry = (*entry & ADDR_MASK) | size | (flags | PageFlags::PRESENT).bits();
+        intrinsics::flush_tlb_entry(virt);
+        Ok(())
+    }
+
+    /// Returns last level entry for `virt` (L2 one for 2MiB pages)
+    fn leaf_entry(&mut self, virt: usize) -> Result<&'static mut u64, MapError> {
+        if virt & (PAGE_SIZE - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let pd = self.next_mut(virt)?.next_mut(virt)?;
+        let pt = match pd.next_mut(virt) {
+            Err(MapError::LargePage) => {
+                if virt & (LARGE_PAGE_SIZE - 1) != 0 {
+                    return Err(MapError::LargePage);
+                }
+                let entry = &mut pd.entries[PageTable::<L2>::index(virt)];
+                return Ok(unsafe { &mut *(entry as *mut _) });
+            },
+            res => res?
+        };
+
+        let entry = &mut pt.entries[PageTable::<L1>::index(virt)];
+        if *entry & PageFlags::PRESENT.bits() == 0 {
+            return Err(MapError::NotMapped);
+        }
+        Ok(entry)
+    }
+}
+
 pub static mut KERNEL: Option<&'static mut Space> = None;
 
 // TODO: add a way to ignore 2MiB pages without using flags
 /// Perform full address translation up to first physical page
-pub fn translate(space: &Space, virt: usize, flags: Option<&mut u64>) -> Option<usize> {
-    let mut inner_flags = 0u64;
+pub fn translate(space: &Space, virt: usize, flags: Option<&mut PageFlags>) -> Option<usize> {
+    let mut inner_flags = PageFlags::empty();
     if let Some(l4_addr) = space.translate(virt, Some(&mut inner_flags)) {
-        if inner_flags & PAGE_HUGE != 0 {
+        if inner_flags.contains(PageFlags::HUGE) {
             panic!();   // Not allowed at this level
         }
 
         let pdpt = unsafe { &*(virtualize(l4_addr) as *const PageTable<L3>) };
         if let Some(l3_addr) = pdpt.translate(virt, Some(&mut inner_flags)) {
-            if inner_flags & PAGE_HUGE != 0 {
-                panic!();       // Sometimes allowed, but we don't do this here
+            if inner_flags.contains(PageFlags::HUGE) {
+                // 1GiB page
+                flags.map(|r| {*r = inner_flags});
+                return Some((l3_addr & !0x3FFFFFFF) | (virt & 0x3FFFFFFF));
             }
 
             let pd = unsafe { &*(virtualize(l3_addr) as *const PageTable<L2>) };
             if let Some(l2_addr) = pd.translate(virt, Some(&mut inner_flags)) {
-                if inner_flags & PAGE_HUGE != 0 {
+                if inner_flags.contains(PageFlags::HUGE) {
                     // 2MiB page
                     flags.map(|r| {*r = inner_flags});
                     Some(l2_addr | (virt & 0x1FFFFF))
@@ -110,3 +437,13 @@ pub fn translate(space: &Space, virt: usize, flags: Option<&mut u64>) -> Option<
         None
     }
 }
+
+/// Enables NX bit support, global pages and write protection
+/// in ring 0 (required for copy-on-write)
+pub fn init() {
+    unsafe {
+        regs::cr0::write(regs::cr0::read() | (1 << 16));
+        regs::wrmsr(regs::MSR_IA32_EFER, regs::rdmsr(regs::MSR_IA32_EFER) | (1 << 11));
+        regs::cr4::write(regs::cr4::read() | (1 << 7));
+    }
+}
@@ -0,0 +1,355 @@
+//! Binary buddy allocator for physical pages
+
+use super::page::{Page, PageUsage};
+
+/// Number of block orders: from a single 4KiB page
+/// (order 0) up to 4MiB blocks (order 10)
+pub const MAX_ORDER: usize = 11;
+
+/// Free list terminator
+const NONE: u32 = 0xFFFFFFFF;
+/// Page is not a head of a free block
+pub const NO_ORDER: u8 = 0xFF;
+
+/// Buddy allocator state. Free lists are intrusive: links
+/// are kept inside `Page` structs of free block heads, so
+/// both allocation and coalescing are O(log n)
+pub struct Buddy<'a> {
+    pages: &'a mut [Page],
+    heads: [u32; MAX_ORDER],
+    free: usize,
+}
+
+/// Smallest order with a block of at least `count` pages
+pub fn order_of(count: usize) -> Option<usize> {
+    let mut order = 0;
+    while (1 << order) < count {
+        order += 1;
+    }
+    if order < MAX_ORDER {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+impl<'a> Buddy<'a> {
+    /// Takes over `pages` marking all of them reserved.
+    /// Page index in the slice is its physical page number
+    pub fn new(pages: &'a mut [Page]) -> Buddy<'a> {
+        for page in pages.iter_mut() {
+            *page = Page::reserved();
+        }
+
+        Buddy {
+            pages,
+            heads: [NONE; MAX_ORDER],
+            free: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn page(&self, index: usize) -> &Page {
+        &self.pages[index]
+    }
+
+    #[inline(always)]
+    pub fn page_mut(&mut self, index: usize) -> &mut Page {
+        &mut self.pages[index]
+    }
+
+    /// Number of tracked pages, starting from 0
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free
+    }
+
+    /// Hands pages `start .. end` over to the allocator,
+    /// splitting the range into largest aligned blocks
+    pub fn add_range(&mut self, start: usize, end: usize) {
+        assert!(end <= self.pages.len());
+
+        let mut index = start;
+        while index < end {
+            let mut order = MAX_ORDER - 1;
+            while index & ((1 << order) - 1) != 0 || index + (1 << order) > end {
+                order -= 1;
+            }
+
+            self.release(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Allocates `count` contiguous pages marking them with
+    /// `usage`, each page holding a single reference. Returned
+    /// index is aligned to `count` rounded up to a power of two
+    pub fn alloc(&mut self, usage: PageUsage, count: usize) -> Option<usize> {
+        assert!(count != 0);
+        let order = order_of(count)?;
+        let index = self.take(order)?;
+
+        for page in &mut self.pages[index .. index + count] {
+            page.usage = usage.clone();
+            page.refcount = 1;
+        }
+
+        // Return unused tail of the block
+        self.add_range(index + count, index + (1 << order));
+
+        Some(index)
+    }
+
+    /// Returns a single page with no references left to the
+    /// allocator, merging it with its buddies if possible
+    pub fn free(&mut self, index: usize) {
+        let page = &self.pages[index];
+        assert!(page.usage != PageUsage::Available && page.usage != PageUsage::Reserved);
+        assert!(page.refcount == 0);
+
+        self.release(index, 0);
+    }
+
+    fn take(&mut self, order: usize) -> Option<usize> {
+        let mut current = order;
+        while self.heads[current] == NONE {
+            current += 1;
+            if current == MAX_ORDER {
+                return None;
+            }
+        }
+
+        let index = self.heads[current] as usize;
+        self.unlink(index, current);
+
+        // Split the block returning upper halves to free lists
+        while current > order {
+            current -= 1;
+            self.link(index + (1 << current), current);
+        }
+
+        self.free -= 1 << order;
+        Some(index)
+    }
+
+    fn release(&mut self, mut index: usize, mut order: usize) {
+        for page in &mut self.pages[index .. index + (1 << order)] {
+            page.usage = PageUsage::Available;
+            page.refcount = 0;
+            page.order = NO_ORDER;
+        }
+        self.free += 1 << order;
+
+        while order < MAX_ORDER - 1 {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.pages.len() || !self.is_free_head(buddy, order) {
+                break;
+            }
+
+            self.unlink(buddy, order);
+            index &= !(1 << order);
+            order += 1;
+        }
+
+        self.link(index, order);
+    }
+
+    #[inline(always)]
+    fn is_free_head(&self, index: usize, order: usize) -> bool {
+        let page = &self.pages[index];
+        page.usage == PageUsage::Available && page.order as usize == order
+    }
+
+    fn link(&mut self, index: usize, order: usize) {
+        let head = self.heads[order];
+        if head != NONE {
+            self.pages[head as usize].prev = index as u32;
+        }
+
+        let page = &mut self.pages[index];
+        page.order = order as u8;
+        page.prev = NONE;
+        page.next = head;
+
+        self.heads[order] = index as u32;
+    }
+
+    fn unlink(&mut self, index: usize, order: usize) {
+        let (prev, next) = {
+            let page = &mut self.pages[index];
+            page.order = NO_ORDER;
+            (page.prev, page.next)
+        };
+
+        if prev != NONE {
+            self.pages[prev as usize].next = next;
+        } else {
+            self.heads[order] = next;
+        }
+        if next != NONE {
+            self.pages[next as usize].prev = prev;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Buddy, MAX_ORDER, order_of};
+    use super::super::page::{Page, PageUsage};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn pages(count: usize) -> Box<[Page]> {
+        (0 .. count).map(|_| Page::reserved()).collect::<Vec<_>>().into_boxed_slice()
+    }
+
+    #[test]
+    fn orders() {
+        assert!(order_of(1) == Some(0));
+        assert!(order_of(2) == Some(1));
+        assert!(order_of(3) == Some(2));
+        assert!(order_of(1 << (MAX_ORDER - 1)) == Some(MAX_ORDER - 1));
+        assert!(order_of((1 << (MAX_ORDER - 1)) + 1).is_none());
+    }
+
+    #[test]
+    fn empty() {
+        let mut buf = pages(64);
+        let mut buddy = Buddy::new(&mut buf);
+
+        assert!(buddy.free_count() == 0);
+        assert!(buddy.alloc(PageUsage::Kernel, 1).is_none());
+    }
+
+    #[test]
+    fn single_pages() {
+        let mut buf = pages(64);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(16, 32);
+        assert!(buddy.free_count() == 16);
+
+        let mut seen = [false; 64];
+        for _ in 0 .. 16 {
+            let index = buddy.alloc(PageUsage::Kernel, 1).unwrap();
+            assert!(index >= 16 && index < 32);
+            assert!(!seen[index]);
+            assert!(buddy.page(index).usage == PageUsage::Kernel);
+            seen[index] = true;
+        }
+
+        assert!(buddy.free_count() == 0);
+        assert!(buddy.alloc(PageUsage::Kernel, 1).is_none());
+    }
+
+    #[test]
+    fn alignment() {
+        let mut buf = pages(128);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(1, 127);
+
+        let i0 = buddy.alloc(PageUsage::Kernel, 4).unwrap();
+        let i1 = buddy.alloc(PageUsage::Kernel, 16).unwrap();
+        let i2 = buddy.alloc(PageUsage::Kernel, 32).unwrap();
+
+        assert!(i0 % 4 == 0);
+        assert!(i1 % 16 == 0);
+        assert!(i2 % 32 == 0);
+    }
+
+    #[test]
+    fn trim_tail() {
+        let mut buf = pages(8);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, 8);
+
+        assert!(buddy.alloc(PageUsage::Kernel, 3) == Some(0));
+        assert!(buddy.free_count() == 5);
+        assert!(buddy.page(3).usage == PageUsage::Available);
+        assert!(buddy.alloc(PageUsage::Kernel, 4) == Some(4));
+        assert!(buddy.alloc(PageUsage::Kernel, 1) == Some(3));
+        assert!(buddy.free_count() == 0);
+    }
+
+    #[test]
+    fn coalesce() {
+        let count = 1 << (MAX_ORDER - 1);
+        let mut buf = pages(count);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, count);
+
+        for _ in 0 .. count {
+            buddy.alloc(PageUsage::Kernel, 1).unwrap();
+        }
+        assert!(buddy.alloc(PageUsage::Kernel, 1).is_none());
+
+        // Free in an order which doesn't let buddies merge
+        // until the second pass
+        for index in (0 .. count).step_by(2) {
+            assert!(buddy.page_mut(index).put());
+            buddy.free(index);
+        }
+        assert!(buddy.alloc(PageUsage::Kernel, 2).is_none());
+        for index in (1 .. count).step_by(2) {
+            assert!(buddy.page_mut(index).put());
+            buddy.free(index);
+        }
+
+        assert!(buddy.free_count() == count);
+        assert!(buddy.alloc(PageUsage::Kernel, count) == Some(0));
+        assert!(buddy.page(count - 1).usage == PageUsage::Kernel);
+    }
+
+    #[test]
+    fn adjacent_ranges() {
+        let mut buf = pages(32);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, 12);
+        buddy.add_range(12, 32);
+
+        assert!(buddy.alloc(PageUsage::Kernel, 32) == Some(0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut buf = pages(8);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, 8);
+
+        let index = buddy.alloc(PageUsage::Kernel, 1).unwrap();
+        buddy.page_mut(index).put();
+        buddy.free(index);
+        buddy.free(index);
+    }
+
+    #[test]
+    fn refcount() {
+        let mut buf = pages(8);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, 8);
+
+        let index = buddy.alloc(PageUsage::Kernel, 1).unwrap();
+        assert!(buddy.page(index).refcount() == 1);
+        assert!(buddy.page_mut(index).get() == 2);
+        assert!(!buddy.page_mut(index).put());
+        assert!(buddy.page_mut(index).put());
+        buddy.free(index);
+
+        assert!(buddy.page(index).refcount() == 0);
+        assert!(buddy.free_count() == 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_referenced() {
+        let mut buf = pages(8);
+        let mut buddy = Buddy::new(&mut buf);
+        buddy.add_range(0, 8);
+
+        let index = buddy.alloc(PageUsage::Kernel, 1).unwrap();
+        buddy.free(index);
+    }
+}
@@ -0,0 +1,229 @@
+use yboot2_proto::MemoryMapInfo;
+use core::mem::size_of;
+use core::cmp::{min, max};
+use crate::{KERNEL_OFFSET, virtualize};
+use crate::sync::IrqDisable;
+use spin::Mutex;
+
+pub mod buddy;
+pub use buddy::Buddy;
+pub mod page;
+pub use page::{Page, PageUsage};
+
+/// Physical memory accessible before the kernel sets up
+/// its own address space
+const EARLY_LIMIT: PhysAddr = 0x100000000;
+
+pub (crate) type PhysAddr = usize;
+
+static mut MEMORY: Option<Buddy<'static>> = None;
+/// Serializes allocation and reference counting
+/// between CPUs
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[inline(always)]
+fn memory() -> &'static mut Buddy<'static> {
+    unsafe { MEMORY.as_mut() }.unwrap()
+}
+
+/// Allocates `count` physically contiguous pages. The returned
+/// address is aligned to `count` pages rounded up to a power of two
+pub fn alloc_contiguous(usage: PageUsage, count: usize) -> Option<PhysAddr> {
+    assert!(usage != PageUsage::Reserved && usage != PageUsage::Available);
+
+    let _irq = IrqDisable::new();
+    let _lock = LOCK.lock();
+    memory().alloc(usage, count).map(|index| index << 12)
+}
+
+pub fn alloc_page(usage: PageUsage) -> Option<PhysAddr> {
+    alloc_contiguous(usage, 1)
+}
+
+/// Page of an allocatable frame, `None` for MMIO and
+/// other memory the allocator doesn't manage
+fn tracked_page(phys: PhysAddr) -> Option<&'static mut Page> {
+    get_page_at(phys).filter(|page| page.usage != PageUsage::Reserved)
+}
+
+/// Frees a page which is not shared with anyone else
+pub fn free_page(phys: PhysAddr) {
+    let page = match tracked_page(phys) {
+        Some(page) => page,
+        None => return
+    };
+    if !page.is_used() {
+        panic!("Double free error");
+    }
+    if page.refcount > 1 {
+        panic!("Freeing a shared page: refcount == {}", page.refcount);
+    }
+    put(phys);
+}
+
+/// Takes a reference to an allocated page (e.g. when the
+/// page gets shared between address spaces). Untracked
+/// frames (e.g. device memory) are ignored, returning 0
+pub fn get(phys: PhysAddr) -> u32 {
+    let _irq = IrqDisable::new();
+    let _lock = LOCK.lock();
+    tracked_page(phys).map_or(0, |page| page.get())
+}
+
+/// Drops a reference to a page, returning it to the free
+/// pool when the last one is gone. Returns `true` in that case
+pub fn put(phys: PhysAddr) -> bool {
+    let _irq = IrqDisable::new();
+    let _lock = LOCK.lock();
+    if tracked_page(phys).map_or(false, |page| page.put()) {
+        memory().free(phys / 4096);
+        true
+    } else {
+        false
+    }
+}
+
+/// Returns `None` past the end of RAM
+pub fn get_page_at(addr: PhysAddr) -> Option<&'static mut Page> {
+    get_page(addr / 4096)
+}
+
+#[inline(always)]
+pub fn get_page(num: usize) -> Option<&'static mut Page> {
+    let memory = memory();
+    if num < memory.page_count() {
+        Some(memory.page_mut(num))
+    } else {
+        None
+    }
+}
+
+fn place_struct(at: PhysAddr, count: usize) {
+    unsafe {
+        let pages = core::slice::from_raw_parts_mut(virtualize(at) as *mut Page, count);
+        MEMORY = Some(Buddy::new(pages));
+    }
+}
+
+extern "C" {
+    static _kernel_end: u8;
+}
+
+fn kernel_end() -> usize {
+    (unsafe { &_kernel_end as *const _ as usize }) - KERNEL_OFFSET
+}
+
+fn is_usable(page: PhysAddr) -> bool {
+    let (initrd_start, initrd_end) = unsafe { INITRD_RANGE };
+    page > kernel_end() && (page < initrd_start || page >= initrd_end)
+}
+
+fn fit_mm_pages(mmap: &MemoryMapInfo, req_count: usize) -> Option<PhysAddr> {
+    let mut collected = 0usize;
+    let mut base_addr = 0usize;
+
+    for item in mmap.iter(true) {
+        let aligned_start = (item.begin() + 0xFFF) & !0xFFF;
+        let aligned_end = min(item.end() & !0xFFF, EARLY_LIMIT);
+
+        if item.is_usable() && aligned_end > aligned_start {
+            for page in (aligned_start .. aligned_end).step_by(0x1000) {
+                if !is_usable(page) {
+                    collected = 0;
+                    base_addr = 0;
+                    continue;
+                }
+
+                if base_addr == 0 {
+                    base_addr = page;
+                }
+                collected += 1;
+                if collected == req_count {
+                    return Some(base_addr);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Page structs and memory map buffer, these must not be
+/// handed out while still in use
+static mut STRUCT_RANGE: (PhysAddr, PhysAddr) = (0, 0);
+static mut MMAP_RANGE: (PhysAddr, PhysAddr) = (0, 0);
+/// Initial ramdisk, files are read from it in place so it's never freed
+static mut INITRD_RANGE: (PhysAddr, PhysAddr) = (0, 0);
+
+/// Adds `start .. end` to the allocator, skipping `holes`
+fn add_range(start: PhysAddr, end: PhysAddr, holes: &[(PhysAddr, PhysAddr)]) {
+    if end <= start {
+        return;
+    }
+
+    if let Some((&(hole_start, hole_end), rest)) = holes.split_first() {
+        add_range(start, min(end, hole_start), rest);
+        add_range(max(start, hole_end), end, rest);
+    } else {
+        memory().add_range(start >> 12, end >> 12);
+    }
+}
+
+fn add_usable(mmap: &MemoryMapInfo, from: PhysAddr, to: PhysAddr, holes: &[(PhysAddr, PhysAddr)]) {
+    let usable_start = (kernel_end() + 0xFFF) & !0xFFF;
+
+    for item in mmap.iter(true) {
+        let aligned_start = max((item.begin() + 0xFFF) & !0xFFF, max(usable_start, from));
+        let aligned_end = min(item.end() & !0xFFF, to);
+
+        if item.is_usable() && aligned_end > aligned_start {
+            add_range(aligned_start, aligned_end, holes);
+        }
+    }
+}
+
+/// Sets up the allocator with memory accessible through
+/// the loader-provided mapping (below 4GiB). `initrd` range
+/// is kept reserved
+pub fn init(mmap: &MemoryMapInfo, initrd: (PhysAddr, PhysAddr)) {
+    unsafe {
+        INITRD_RANGE = (initrd.0 & !0xFFF, (initrd.1 + 0xFFF) & !0xFFF);
+    }
+
+    // Page structs cover all the usable memory
+    let count = mmap.iter(true)
+        .filter(|item| item.is_usable())
+        .map(|item| item.end() >> 12)
+        .max()
+        .unwrap();
+    let struct_size = count * size_of::<Page>();
+    let pages_addr = fit_mm_pages(mmap, (struct_size + 0xFFF) / 0x1000).unwrap();
+    // TODO: make sure fit_mm_pages just doesn't pick addresses which would
+    //       screw up the memory map
+    assert!(pages_addr > mmap.address as usize + mmap.size as usize ||
+            pages_addr + struct_size < mmap.address as usize);
+
+    place_struct(pages_addr, count);
+
+    unsafe {
+        STRUCT_RANGE = (pages_addr, (pages_addr + struct_size + 0xFFF) & !0xFFF);
+        MMAP_RANGE = (mmap.address as usize & !0xFFF,
+                      (mmap.address as usize + mmap.size as usize + 0xFFF) & !0xFFF);
+
+        // Memory map is still needed to finish the initialization
+        add_usable(mmap, 0, EARLY_LIMIT, &[STRUCT_RANGE, MMAP_RANGE, INITRD_RANGE]);
+    }
+
+    println!("Physical memory: {}K available", memory().free_count() * 4);
+}
+
+/// Adds the rest of the memory once it's accessible through
+/// `virtualize`. The memory map is no longer needed after this
+pub fn init_late(mmap: &MemoryMapInfo) {
+    unsafe {
+        add_usable(mmap, EARLY_LIMIT, usize::MAX, &[STRUCT_RANGE, INITRD_RANGE]);
+        add_usable(mmap, MMAP_RANGE.0, min(MMAP_RANGE.1, EARLY_LIMIT), &[STRUCT_RANGE, INITRD_RANGE]);
+    }
+
+    println!("Physical memory: {}K available", memory().free_count() * 4);
+}
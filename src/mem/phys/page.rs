@@ -0,0 +1,66 @@
+//! Per-frame bookkeeping, shared by the allocator and
+//! reference counting
+
+use super::buddy;
+
+#[derive(PartialEq, Clone, Debug)]
+#[repr(u8)]
+pub enum PageUsage {
+    Reserved,
+    Available,
+    Kernel,
+    Paging,
+    User,
+}
+
+pub struct Page {
+    pub(super) refcount: u32,
+    pub(super) usage: PageUsage,
+    // Buddy allocator free list links
+    pub(super) order: u8,
+    pub(super) prev: u32,
+    pub(super) next: u32,
+}
+
+impl Page {
+    pub const fn reserved() -> Page {
+        Page {
+            refcount: 0,
+            usage: PageUsage::Reserved,
+            order: buddy::NO_ORDER,
+            prev: 0,
+            next: 0,
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.usage != PageUsage::Available
+    }
+
+    pub fn usage(&self) -> PageUsage {
+        self.usage.clone()
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount
+    }
+
+    /// Takes an extra reference to an allocated page,
+    /// returns the new reference count
+    pub fn get(&mut self) -> u32 {
+        assert!(self.is_used() && self.usage != PageUsage::Reserved);
+        self.refcount += 1;
+        self.refcount
+    }
+
+    /// Drops a reference, returns `true` if it was the
+    /// last one and the page has to be freed
+    pub fn put(&mut self) -> bool {
+        assert!(self.is_used() && self.usage != PageUsage::Reserved);
+        if self.refcount == 0 {
+            panic!("Refcount == 0");
+        }
+        self.refcount -= 1;
+        self.refcount == 0
+    }
+}
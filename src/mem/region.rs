@@ -0,0 +1,206 @@
+//! Virtual memory regions of user processes and demand paging
+
+use super::{PageFlags, Space, MapError, PAGE_SIZE, USER_END, translate};
+use super::phys::{self, PageUsage};
+use crate::thread;
+use crate::virtualize;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+/// Source of page contents for file-backed regions
+pub trait Backing {
+    /// Reads data at `offset` into `buf`, returns the
+    /// number of bytes read
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize;
+    /// Size of the data in bytes
+    fn size(&self) -> usize;
+}
+
+/// In-memory images, e.g. files from initrd
+impl Backing for &'static [u8] {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if offset >= self.len() {
+            return 0;
+        }
+        let len = min(buf.len(), self.len() - offset);
+        buf[.. len].copy_from_slice(&self[offset .. offset + len]);
+        len
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone)]
+pub enum RegionKind {
+    /// Zero-filled memory
+    Anonymous,
+    /// Zero-filled memory used as a thread stack
+    Stack,
+    /// First `size` bytes are read from `backing` at `offset`,
+    /// the rest is zero-filled
+    File {
+        backing:    Arc<dyn Backing>,
+        offset:     usize,
+        size:       usize,
+    },
+}
+
+#[derive(Clone)]
+pub struct Region {
+    pub start:  usize,
+    pub end:    usize,
+    /// Access rights of pages mapped in the region
+    pub flags:  PageFlags,
+    pub kind:   RegionKind,
+}
+
+/// Regions of a single address space, sorted by address
+#[derive(Clone)]
+pub struct RegionList {
+    regions: Vec<Region>,
+}
+
+impl Region {
+    pub fn new(start: usize, end: usize, flags: PageFlags, kind: RegionKind) -> Region {
+        assert!(start & (PAGE_SIZE - 1) == 0 && end & (PAGE_SIZE - 1) == 0);
+        assert!(start < end && end <= USER_END);
+
+        Region {
+            start,
+            end,
+            flags: flags | PageFlags::USER,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read    => true,
+            Access::Write   => self.flags.contains(PageFlags::WRITE),
+            Access::Execute => !self.flags.contains(PageFlags::NX),
+        }
+    }
+
+    /// Allocates, fills and maps a page at `page`
+    pub fn populate(&self, space: &mut Space, page: usize) -> Result<(), MapError> {
+        assert!(self.contains(page));
+        let phys = phys::alloc_page(PageUsage::User).ok_or(MapError::OutOfMemory)?;
+        let data = unsafe {
+            core::slice::from_raw_parts_mut(virtualize(phys) as *mut u8, PAGE_SIZE)
+        };
+
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        if let RegionKind::File { backing, offset, size } = &self.kind {
+            let rel = page - self.start;
+            if rel < *size {
+                let len = min(PAGE_SIZE, size - rel);
+                backing.read(offset + rel, &mut data[.. len]);
+            }
+        }
+
+        space.map(page, phys, self.flags).map_err(|err| {
+            phys::free_page(phys);
+            err
+        })
+    }
+
+    /// Resolves a write to a copy-on-write page at `page`:
+    /// the page is copied unless this space is its last user
+    pub fn copy_on_write(&self, space: &mut Space, page: usize) -> Result<(), MapError> {
+        let mut flags = PageFlags::empty();
+        let phys = translate(space, page, Some(&mut flags)).ok_or(MapError::NotMapped)?;
+        if !flags.contains(PageFlags::COW) {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        if phys::get_page_at(phys).map_or(false, |page| page.refcount() == 1) {
+            return space.protect(page, self.flags);
+        }
+
+        let copy = phys::alloc_page(PageUsage::User).ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(virtualize(phys) as *const u8,
+                                           virtualize(copy) as *mut u8,
+                                           PAGE_SIZE);
+        }
+
+        space.unmap(page)?;
+        phys::put(phys);
+        space.map(page, copy, self.flags)
+    }
+}
+
+impl RegionList {
+    pub const fn new() -> RegionList {
+        RegionList {
+            regions: Vec::new()
+        }
+    }
+
+    /// Registers a region, fails if it overlaps an existing one
+    pub fn add(&mut self, region: Region) -> Result<(), MapError> {
+        let index = self.regions.iter().position(|r| r.start >= region.end)
+            .unwrap_or(self.regions.len());
+
+        if index > 0 && self.regions[index - 1].end > region.start {
+            return Err(MapError::AlreadyMapped);
+        }
+
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    pub fn find(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+}
+
+/// Resolves a page fault at `addr` in the current process.
+/// Returns `false` if the access is illegal
+pub fn handle_fault(addr: usize, access: Access, present: bool) -> bool {
+    if addr >= USER_END {
+        return false;
+    }
+
+    let current = thread::current();
+    if current.is_null() {
+        return false;
+    }
+    let process = unsafe { &mut *(*current).owner };
+    let page = addr & !(PAGE_SIZE - 1);
+
+    let region = match process.regions.find(addr) {
+        Some(region) => region,
+        None => return false
+    };
+    if !region.allows(access) {
+        return false;
+    }
+
+    if present {
+        // Only writes to copy-on-write pages are resolvable
+        access == Access::Write && region.copy_on_write(process.space, page).is_ok()
+    } else {
+        region.populate(process.space, page).is_ok()
+    }
+}
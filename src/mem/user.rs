@@ -0,0 +1,74 @@
+//! Access to memory of the current user process. Bad
+//! pointers result in `Fault` rather than a kernel crash
+
+use super::USER_END;
+use crate::arch::x86::usercopy;
+use crate::thread;
+use core::mem::{size_of, MaybeUninit};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fault;
+
+fn is_user_process() -> bool {
+    let current = thread::current();
+    !current.is_null() && unsafe { (*(*current).owner).is_user }
+}
+
+/// Checks `addr .. addr + len` is within user half
+fn check_range(addr: usize, len: usize) -> Result<(), Fault> {
+    if !is_user_process() {
+        return Err(Fault);
+    }
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(Fault)
+    }
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Fault> {
+    check_range(src, dst.len())?;
+    match unsafe { usercopy::copy_user(dst.as_mut_ptr() as usize, src, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Fault)
+    }
+}
+
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Fault> {
+    check_range(dst, src.len())?;
+    match unsafe { usercopy::copy_user(dst, src.as_ptr() as usize, src.len()) } {
+        0 => Ok(()),
+        _ => Err(Fault)
+    }
+}
+
+/// Copies a NUL-terminated string at `src` into `dst`, returns
+/// its length. If the string doesn't fit, `dst.len()` is returned
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize, Fault> {
+    check_range(src, 1)?;
+    // Don't let the copy run past the user half
+    let max = core::cmp::min(dst.len(), USER_END - src);
+    let len = unsafe { usercopy::strncpy_user(dst.as_mut_ptr() as usize, src, max) }.ok_or(Fault)?;
+
+    if len == max && max < dst.len() {
+        // Unterminated string at the end of user memory
+        return Err(Fault);
+    }
+    Ok(len)
+}
+
+pub fn read_user<T: Copy>(src: usize) -> Result<T, Fault> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+    };
+
+    copy_from_user(buf, src)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+pub fn write_user<T: Copy>(dst: usize, value: &T) -> Result<(), Fault> {
+    let buf = unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+    };
+    copy_to_user(dst, buf)
+}
@@ -1,3 +1,11 @@
+//! Synchronization primitives. `IrqDisable` keeps the code on
+//! the current CPU uninterrupted, the rest put contending
+//! threads to sleep
+
+use crate::thread::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
 pub struct IrqDisable {
     saved_rflags: u64
 }
@@ -19,3 +27,135 @@ impl Drop for IrqDisable {
         }
     }
 }
+
+/// Counting semaphore, `acquire` sleeps while the count is zero
+pub struct Semaphore {
+    count: spin::Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: spin::Mutex::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        // Can be released from an IRQ handler
+        let _irq = IrqDisable::new();
+        loop {
+            let mut count = self.count.lock();
+            if *count != 0 {
+                *count -= 1;
+                return;
+            }
+            self.waiters.wait_then(move || drop(count));
+        }
+    }
+
+    /// Takes the semaphore only if it's available right away
+    pub fn try_acquire(&self) -> bool {
+        let _irq = IrqDisable::new();
+        let mut count = self.count.lock();
+        if *count != 0 {
+            *count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn release(&self) {
+        let _irq = IrqDisable::new();
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+}
+
+/// Lock which puts contending threads to sleep, not to be
+/// used from IRQ handlers or before scheduling starts
+pub struct Mutex<T> {
+    sem: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            sem: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.sem.acquire();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.sem.try_acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.sem.release();
+    }
+}
+
+/// Condition variable to be used along with `Mutex`
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex and sleeps until notified, then
+    /// locks it again. Wakeups may be spurious, so the
+    /// condition has to be rechecked
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        self.waiters.wait_then(move || drop(guard));
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
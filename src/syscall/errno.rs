@@ -0,0 +1,17 @@
+//! Error numbers returned by system calls, same as on Linux
+
+pub const EPERM: isize          = 1;
+pub const ENOENT: isize         = 2;
+pub const ESRCH: isize          = 3;
+pub const EBADF: isize          = 9;
+pub const ENOMEM: isize         = 12;
+pub const EFAULT: isize         = 14;
+pub const EBUSY: isize          = 16;
+pub const ENOTDIR: isize        = 20;
+pub const EISDIR: isize         = 21;
+pub const EINVAL: isize         = 22;
+pub const EMFILE: isize         = 24;
+pub const EROFS: isize          = 30;
+pub const ENAMETOOLONG: isize   = 36;
+pub const ENOSYS: isize         = 38;
+pub const ELOOP: isize          = 40;
@@ -0,0 +1,294 @@
+//! File-related system calls
+
+use super::{current_process, fs_error, user_path};
+use super::errno::*;
+use crate::fs::{File, FileRef, OpenFlags, Stat, VnodeKind, Whence, vfs};
+use crate::mem::user::{copy_from_user, copy_to_user, write_user};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::cmp::min;
+use core::mem::size_of;
+use spin::Mutex;
+
+/// Data is passed between files and user memory through
+/// a bounce buffer of at most this size
+const IO_CHUNK: usize = 4096;
+
+const S_IFCHR: u32  = 0o020000;
+const S_IFDIR: u32  = 0o040000;
+const S_IFREG: u32  = 0o100000;
+const S_IFLNK: u32  = 0o120000;
+
+const DT_CHR: u8    = 2;
+const DT_DIR: u8    = 4;
+const DT_REG: u8    = 8;
+const DT_LNK: u8    = 10;
+
+/// `struct stat` as seen by Linux x86-64 programs
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UserStat {
+    dev:        u64,
+    ino:        u64,
+    nlink:      u64,
+    mode:       u32,
+    uid:        u32,
+    gid:        u32,
+    _pad0:      u32,
+    rdev:       u64,
+    size:       i64,
+    blksize:    i64,
+    blocks:     i64,
+    atime:      u64,
+    atime_nsec: u64,
+    mtime:      u64,
+    mtime_nsec: u64,
+    ctime:      u64,
+    ctime_nsec: u64,
+    _reserved:  [i64; 3],
+}
+
+/// Header of `struct linux_dirent64`, followed by
+/// NUL-terminated name
+#[repr(C, packed)]
+struct UserDirent {
+    ino:        u64,
+    off:        i64,
+    reclen:     u16,
+    kind:       u8,
+}
+
+fn file(fd: usize) -> Result<FileRef, isize> {
+    current_process().files.get(fd).map_err(fs_error)
+}
+
+fn copy_stat(stat: Stat, buf: usize) -> isize {
+    let kind = match stat.kind {
+        VnodeKind::Regular      => S_IFREG,
+        VnodeKind::Directory    => S_IFDIR,
+        VnodeKind::Symlink      => S_IFLNK,
+        VnodeKind::CharDevice   => S_IFCHR,
+    };
+    let data = UserStat {
+        nlink:      1,
+        mode:       kind | stat.mode,
+        uid:        stat.uid,
+        gid:        stat.gid,
+        size:       stat.size as i64,
+        blksize:    4096,
+        blocks:     ((stat.size + 511) / 512) as i64,
+        mtime:      stat.mtime,
+        ctime:      stat.mtime,
+        atime:      stat.mtime,
+        ..UserStat::default()
+    };
+
+    match write_user(buf, &data) {
+        Ok(()) => 0,
+        Err(_) => -EFAULT
+    }
+}
+
+fn stat_path(path: usize, buf: usize, follow: bool) -> isize {
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(err) => return err
+    };
+
+    match vfs::lookup(&current_process().cwd, &path, follow) {
+        Ok((_, node)) => copy_stat(node.stat(), buf),
+        Err(err) => fs_error(err)
+    }
+}
+
+pub fn sys_read(fd: usize, buf: usize, count: usize) -> isize {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(err) => return err
+    };
+    let mut file = file.lock();
+    let mut chunk = vec![0u8; min(count, IO_CHUNK)];
+    let mut done = 0;
+
+    while done < count {
+        let len = min(count - done, IO_CHUNK);
+        let read = match file.read(&mut chunk[.. len]) {
+            Ok(read) => read,
+            Err(err) if done == 0 => return fs_error(err),
+            Err(_) => break
+        };
+
+        if copy_to_user(buf + done, &chunk[.. read]).is_err() {
+            return if done == 0 { -EFAULT } else { done as isize };
+        }
+        done += read;
+        if read < len {
+            break;
+        }
+    }
+
+    done as isize
+}
+
+pub fn sys_write(fd: usize, buf: usize, count: usize) -> isize {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(err) => return err
+    };
+    let mut file = file.lock();
+    let mut chunk = vec![0u8; min(count, IO_CHUNK)];
+    let mut done = 0;
+
+    while done < count {
+        let len = min(count - done, IO_CHUNK);
+        if copy_from_user(&mut chunk[.. len], buf + done).is_err() {
+            return if done == 0 { -EFAULT } else { done as isize };
+        }
+
+        let written = match file.write(&chunk[.. len]) {
+            Ok(written) => written,
+            Err(err) if done == 0 => return fs_error(err),
+            Err(_) => break
+        };
+        done += written;
+        if written < len {
+            break;
+        }
+    }
+
+    done as isize
+}
+
+pub fn sys_open(path: usize, flags: u32, _mode: u32) -> isize {
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(err) => return err
+    };
+    let flags = OpenFlags::from_bits_truncate(flags);
+    let process = current_process();
+
+    let file = match File::open(&process.cwd, &path, flags) {
+        Ok(file) => file,
+        Err(err) => return fs_error(err)
+    };
+
+    match process.files.insert(Arc::new(Mutex::new(file))) {
+        Ok(fd) => fd as isize,
+        Err(err) => fs_error(err)
+    }
+}
+
+pub fn sys_close(fd: usize) -> isize {
+    match current_process().files.close(fd) {
+        Ok(()) => 0,
+        Err(err) => fs_error(err)
+    }
+}
+
+pub fn sys_stat(path: usize, buf: usize) -> isize {
+    stat_path(path, buf, true)
+}
+
+pub fn sys_lstat(path: usize, buf: usize) -> isize {
+    stat_path(path, buf, false)
+}
+
+pub fn sys_fstat(fd: usize, buf: usize) -> isize {
+    match file(fd) {
+        Ok(file) => {
+            let stat = file.lock().stat();
+            copy_stat(stat, buf)
+        },
+        Err(err) => err
+    }
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: u32) -> isize {
+    let whence = match whence {
+        0 => Whence::Set,
+        1 => Whence::Current,
+        2 => Whence::End,
+        _ => return -EINVAL
+    };
+
+    match file(fd).and_then(|f| f.lock().seek(offset, whence).map_err(fs_error)) {
+        Ok(offset) => offset as isize,
+        Err(err) => err
+    }
+}
+
+pub fn sys_dup(fd: usize) -> isize {
+    match current_process().files.dup(fd) {
+        Ok(fd) => fd as isize,
+        Err(err) => fs_error(err)
+    }
+}
+
+pub fn sys_dup2(fd: usize, new_fd: usize) -> isize {
+    match current_process().files.dup2(fd, new_fd) {
+        Ok(fd) => fd as isize,
+        Err(err) => fs_error(err)
+    }
+}
+
+/// Fills `buf` with as many directory entries as fit, returns
+/// the number of bytes written, 0 at the end of directory
+pub fn sys_getdents64(fd: usize, buf: usize, count: usize) -> isize {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(err) => return err
+    };
+    let mut file = file.lock();
+
+    let mut data = Vec::new();
+    let mut entries = 0;
+    loop {
+        let entry = match file.readdir() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(err) => return fs_error(err)
+        };
+
+        // Records are 8-byte aligned
+        let reclen = (size_of::<UserDirent>() + entry.name.len() + 1 + 7) & !7;
+        if data.len() + reclen > count {
+            // Leave the entry for the next call
+            file.seek(-1, Whence::Current).unwrap();
+            if entries == 0 {
+                return -EINVAL;
+            }
+            break;
+        }
+
+        // No inode numbers yet, position of the next entry will do
+        let next = file.seek(0, Whence::Current).unwrap();
+        let header = UserDirent {
+            ino:    next as u64,
+            off:    next as i64,
+            reclen: reclen as u16,
+            kind:   match entry.kind {
+                VnodeKind::Regular      => DT_REG,
+                VnodeKind::Directory    => DT_DIR,
+                VnodeKind::Symlink      => DT_LNK,
+                VnodeKind::CharDevice   => DT_CHR,
+            },
+        };
+
+        let pos = data.len();
+        data.resize(pos + reclen, 0);
+        let record = &mut data[pos ..];
+        unsafe { core::ptr::write_unaligned(record.as_mut_ptr() as *mut UserDirent, header); }
+        let name = size_of::<UserDirent>();
+        record[name .. name + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+
+        entries += 1;
+    }
+
+    if copy_to_user(buf, &data).is_err() {
+        // Entries haven't reached the caller
+        file.seek(-entries, Whence::Current).unwrap();
+        return -EFAULT;
+    }
+    data.len() as isize
+}
@@ -0,0 +1,178 @@
+//! System call handlers. Numbers and argument order follow
+//! Linux x86-64. A handler returns its result in `rax`:
+//! non-negative values mean success, `-errno` means failure
+//! (see `errno` for the values)
+
+use crate::thread::{self, Process};
+use crate::arch::x86::syscall::SyscallFrame;
+use crate::mem::user;
+use crate::fs;
+use alloc::string::String;
+use alloc::vec;
+
+pub mod errno;
+use errno::*;
+mod file;
+mod sched;
+mod time;
+
+/// Handler receives raw argument registers in ABI order
+pub type Handler = fn(&[usize; 6]) -> isize;
+
+pub const MAX_SYSCALL: usize = 256;
+static mut SYSCALL_TABLE: [Option<Handler>; MAX_SYSCALL] = [None; MAX_SYSCALL];
+
+/// Value which can be passed in an argument register
+pub trait SyscallArg {
+    fn from_arg(value: usize) -> Self;
+}
+
+impl SyscallArg for usize {
+    fn from_arg(value: usize) -> usize { value }
+}
+
+impl SyscallArg for isize {
+    fn from_arg(value: usize) -> isize { value as isize }
+}
+
+impl SyscallArg for u32 {
+    fn from_arg(value: usize) -> u32 { value as u32 }
+}
+
+impl SyscallArg for i32 {
+    fn from_arg(value: usize) -> i32 { value as i32 }
+}
+
+/// Registers handlers with typed signatures:
+///
+/// ```ignore
+/// syscalls! {
+///     SYS_READ => file::sys_read(usize, usize, usize);
+/// }
+/// ```
+///
+/// Argument types have to match the handler's, at most six
+macro_rules! syscalls {
+    ($($n:expr => $($handler:ident)::+ ($($arg:ty),*);)*) => {
+        $(
+            SYSCALL_TABLE[$n] = Some(|_args: &[usize; 6]| -> isize {
+                let mut _args = _args.iter();
+                $($handler)::+($(<$arg as SyscallArg>::from_arg(*_args.next().unwrap())),*)
+            });
+        )*
+    }
+}
+
+pub const SYS_READ: usize                   = 0;
+pub const SYS_WRITE: usize                  = 1;
+pub const SYS_OPEN: usize                   = 2;
+pub const SYS_CLOSE: usize                  = 3;
+pub const SYS_STAT: usize                   = 4;
+pub const SYS_FSTAT: usize                  = 5;
+pub const SYS_LSTAT: usize                  = 6;
+pub const SYS_LSEEK: usize                  = 8;
+pub const SYS_SCHED_YIELD: usize            = 24;
+pub const SYS_DUP: usize                    = 32;
+pub const SYS_DUP2: usize                   = 33;
+pub const SYS_NANOSLEEP: usize              = 35;
+pub const SYS_FORK: usize                   = 57;
+pub const SYS_GETPRIORITY: usize            = 140;
+pub const SYS_SETPRIORITY: usize            = 141;
+pub const SYS_SCHED_SETPARAM: usize         = 142;
+pub const SYS_SCHED_GETPARAM: usize         = 143;
+pub const SYS_SCHED_SETSCHEDULER: usize     = 144;
+pub const SYS_SCHED_GETSCHEDULER: usize     = 145;
+pub const SYS_SCHED_GET_PRIORITY_MAX: usize = 146;
+pub const SYS_SCHED_GET_PRIORITY_MIN: usize = 147;
+pub const SYS_GETDENTS64: usize             = 217;
+pub const SYS_CLOCK_GETTIME: usize          = 228;
+
+fn current_process() -> &'static mut Process {
+    unsafe { &mut *(*thread::current()).owner }
+}
+
+fn fs_error(err: fs::Error) -> isize {
+    use fs::Error;
+    -match err {
+        Error::NotFound         => ENOENT,
+        Error::NotDirectory     => ENOTDIR,
+        Error::IsDirectory      => EISDIR,
+        Error::ReadOnly         => EROFS,
+        Error::Loop             => ELOOP,
+        Error::NameTooLong      => ENAMETOOLONG,
+        Error::InvalidArgument  => EINVAL,
+        Error::BadDescriptor    => EBADF,
+        Error::TooManyFiles     => EMFILE,
+        Error::Busy             => EBUSY,
+    }
+}
+
+/// Copies a path from user memory
+fn user_path(ptr: usize) -> Result<String, isize> {
+    let mut buf = vec![0u8; fs::vfs::PATH_MAX];
+    let len = user::strncpy_from_user(&mut buf, ptr).map_err(|_| -EFAULT)?;
+    if len == buf.len() {
+        return Err(-ENAMETOOLONG);
+    }
+
+    buf.truncate(len);
+    String::from_utf8(buf).map_err(|_| -EINVAL)
+}
+
+/// Called from `syscall_entry` with user registers
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> isize {
+    let handler = unsafe { SYSCALL_TABLE.get(frame.rax).cloned().flatten() };
+
+    match handler {
+        Some(handler) => handler(&frame.args()),
+        None => -ENOSYS
+    }
+}
+
+/// Returns child PID to the parent and 0 to the child
+fn sys_fork() -> isize {
+    let thread = unsafe { &*thread::current() };
+    let process = unsafe { &mut *thread.owner };
+
+    match process.fork(thread) {
+        Some(pid) => pid as isize,
+        None => -ENOMEM
+    }
+}
+
+pub fn init() {
+    unsafe {
+        syscalls! {
+            SYS_READ                    => file::sys_read(usize, usize, usize);
+            SYS_WRITE                   => file::sys_write(usize, usize, usize);
+            SYS_OPEN                    => file::sys_open(usize, u32, u32);
+            SYS_CLOSE                   => file::sys_close(usize);
+            SYS_STAT                    => file::sys_stat(usize, usize);
+            SYS_FSTAT                   => file::sys_fstat(usize, usize);
+            SYS_LSTAT                   => file::sys_lstat(usize, usize);
+            SYS_LSEEK                   => file::sys_lseek(usize, isize, u32);
+            SYS_DUP                     => file::sys_dup(usize);
+            SYS_DUP2                    => file::sys_dup2(usize, usize);
+            SYS_FORK                    => sys_fork();
+            SYS_GETDENTS64              => file::sys_getdents64(usize, usize, usize);
+
+            SYS_SCHED_YIELD             => sched::sys_sched_yield();
+            SYS_GETPRIORITY             => sched::sys_getpriority(i32, i32);
+            SYS_SETPRIORITY             => sched::sys_setpriority(i32, i32, i32);
+            SYS_SCHED_SETPARAM          => sched::sys_sched_setparam(i32, usize);
+            SYS_SCHED_GETPARAM          => sched::sys_sched_getparam(i32, usize);
+            SYS_SCHED_SETSCHEDULER      => sched::sys_sched_setscheduler(i32, i32, usize);
+            SYS_SCHED_GETSCHEDULER      => sched::sys_sched_getscheduler(i32);
+            SYS_SCHED_GET_PRIORITY_MAX  => sched::sys_sched_get_priority_max(i32);
+            SYS_SCHED_GET_PRIORITY_MIN  => sched::sys_sched_get_priority_min(i32);
+
+            SYS_NANOSLEEP               => time::sys_nanosleep(usize, usize);
+            SYS_CLOCK_GETTIME           => time::sys_clock_gettime(u32, usize);
+        }
+    }
+
+    // Platform-specific init
+    use crate::arch::x86;
+    x86::syscall::init();
+}
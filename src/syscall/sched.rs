@@ -0,0 +1,149 @@
+//! Scheduling parameter system calls. Parameters are per-thread,
+//! the only thread which can be targeted is the calling one
+//! (as 0 or its process ID)
+
+use super::errno::*;
+use crate::mem::user::{read_user, write_user};
+use crate::thread::{self, Policy, Thread};
+use crate::thread::{MIN_NICE, MAX_NICE, MIN_RT_PRIORITY, MAX_RT_PRIORITY};
+use core::cmp::{min, max};
+
+const PRIO_PROCESS: i32 = 0;
+
+const SCHED_OTHER: i32  = 0;
+const SCHED_FIFO: i32   = 1;
+const SCHED_RR: i32     = 2;
+
+/// `struct sched_param`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SchedParam {
+    priority: i32,
+}
+
+fn target(pid: i32) -> Result<&'static mut Thread, isize> {
+    let thread = unsafe { &mut *thread::current() };
+    if pid < 0 {
+        Err(-EINVAL)
+    } else if pid == 0 || pid == unsafe { (*thread.owner).id } {
+        Ok(thread)
+    } else {
+        Err(-ESRCH)
+    }
+}
+
+fn policy(value: i32) -> Result<Policy, isize> {
+    match value {
+        SCHED_OTHER => Ok(Policy::Fair),
+        SCHED_FIFO  => Ok(Policy::Fifo),
+        SCHED_RR    => Ok(Policy::RoundRobin),
+        _           => Err(-EINVAL)
+    }
+}
+
+/// Valid priorities of a policy
+fn priority_range(policy: Policy) -> (i32, i32) {
+    match policy {
+        Policy::Fair => (0, 0),
+        _ => (MIN_RT_PRIORITY as i32, MAX_RT_PRIORITY as i32)
+    }
+}
+
+fn set_param(thread: &mut Thread, policy: Policy, param: usize) -> isize {
+    let param: SchedParam = match read_user(param) {
+        Ok(param) => param,
+        Err(_) => return -EFAULT
+    };
+    let (low, high) = priority_range(policy);
+    if param.priority < low || param.priority > high {
+        return -EINVAL;
+    }
+
+    thread.set_policy(policy, param.priority as u8);
+    0
+}
+
+pub fn sys_sched_yield() -> isize {
+    unsafe { thread::r#yield(); }
+    0
+}
+
+/// Returns `20 - nice` (1 .. 40), so that the result
+/// is never negative
+pub fn sys_getpriority(which: i32, who: i32) -> isize {
+    if which != PRIO_PROCESS {
+        return -EINVAL;
+    }
+    match target(who) {
+        Ok(thread) => 20 - thread.nice() as isize,
+        Err(err) => err
+    }
+}
+
+/// Out of range `nice` values are clamped
+pub fn sys_setpriority(which: i32, who: i32, nice: i32) -> isize {
+    if which != PRIO_PROCESS {
+        return -EINVAL;
+    }
+    match target(who) {
+        Ok(thread) => {
+            thread.set_nice(max(MIN_NICE as i32, min(MAX_NICE as i32, nice)) as i8);
+            0
+        },
+        Err(err) => err
+    }
+}
+
+pub fn sys_sched_setparam(pid: i32, param: usize) -> isize {
+    match target(pid) {
+        Ok(thread) => {
+            let policy = thread.policy();
+            set_param(thread, policy, param)
+        },
+        Err(err) => err
+    }
+}
+
+pub fn sys_sched_getparam(pid: i32, param: usize) -> isize {
+    let thread = match target(pid) {
+        Ok(thread) => thread,
+        Err(err) => return err
+    };
+
+    match write_user(param, &SchedParam { priority: thread.priority() as i32 }) {
+        Ok(()) => 0,
+        Err(_) => -EFAULT
+    }
+}
+
+pub fn sys_sched_setscheduler(pid: i32, policy_value: i32, param: usize) -> isize {
+    match (target(pid), policy(policy_value)) {
+        (Ok(thread), Ok(policy)) => set_param(thread, policy, param),
+        (Err(err), _) | (_, Err(err)) => err
+    }
+}
+
+pub fn sys_sched_getscheduler(pid: i32) -> isize {
+    match target(pid) {
+        Ok(thread) => match thread.policy() {
+            Policy::Fair        => SCHED_OTHER as isize,
+            Policy::Fifo        => SCHED_FIFO as isize,
+            Policy::RoundRobin  => SCHED_RR as isize,
+        },
+        Err(err) => err
+    }
+}
+
+pub fn sys_sched_get_priority_max(policy_value: i32) -> isize {
+    match policy(policy_value) {
+        Ok(policy) => priority_range(policy).1 as isize,
+        Err(err) => err
+    }
+}
+
+pub fn sys_sched_get_priority_min(policy_value: i32) -> isize {
+    match policy(policy_value) {
+        Ok(policy) => priority_range(policy).0 as isize,
+        Err(err) => err
+    }
+}
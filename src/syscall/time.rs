@@ -0,0 +1,64 @@
+//! Clock and sleep system calls
+
+use super::errno::*;
+use crate::mem::user::{read_user, write_user};
+use crate::time::{self, NSEC_PER_SEC};
+
+const CLOCK_REALTIME: u32       = 0;
+const CLOCK_MONOTONIC: u32      = 1;
+const CLOCK_MONOTONIC_RAW: u32  = 4;
+const CLOCK_BOOTTIME: u32       = 7;
+
+/// `struct timespec`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    sec:    i64,
+    nsec:   i64,
+}
+
+impl Timespec {
+    fn from_ns(ns: u64) -> Timespec {
+        Timespec {
+            sec:    (ns / NSEC_PER_SEC) as i64,
+            nsec:   (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+
+    fn to_ns(&self) -> Option<u64> {
+        if self.sec < 0 || self.nsec < 0 || self.nsec >= NSEC_PER_SEC as i64 {
+            return None;
+        }
+        (self.sec as u64).checked_mul(NSEC_PER_SEC)?.checked_add(self.nsec as u64)
+    }
+}
+
+/// Nothing interrupts sleeps yet, so `rem` is never written
+pub fn sys_nanosleep(req: usize, _rem: usize) -> isize {
+    let req: Timespec = match read_user(req) {
+        Ok(req) => req,
+        Err(_) => return -EFAULT
+    };
+
+    match req.to_ns() {
+        Some(ns) => {
+            time::sleep(ns);
+            0
+        },
+        None => -EINVAL
+    }
+}
+
+pub fn sys_clock_gettime(clock: u32, tp: usize) -> isize {
+    let ns = match clock {
+        CLOCK_REALTIME => time::realtime(),
+        // No suspend, so boot time is the same
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_BOOTTIME => time::monotonic(),
+        _ => return -EINVAL
+    };
+
+    match write_user(tp, &Timespec::from_ns(ns)) {
+        Ok(()) => 0,
+        Err(_) => -EFAULT
+    }
+}
@@ -0,0 +1,231 @@
+pub use crate::arch::x86::context::Context;
+use crate::mem::{self, Space, region::RegionList};
+use crate::fs::FdTable;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+mod sched;
+mod wait;
+pub use wait::WaitQueue;
+pub use sched::{Policy, MAX_CPUS, MIN_NICE, MAX_NICE, MIN_RT_PRIORITY, MAX_RT_PRIORITY};
+pub use sched::{current, enter, exit_current, preempt, r#yield, tick};
+
+pub struct Process {
+    pub id: i32,
+    pub is_user: bool,
+    pub head: *mut Thread,
+    /// Kernel processes use kernel space directly, user
+    /// ones own a space sharing kernel's upper half
+    pub space: &'static mut Space,
+    pub regions: RegionList,
+    pub files: FdTable,
+    /// Canonical path of the working directory
+    pub cwd: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    /// Being executed by a CPU
+    Running,
+    /// Waiting in a run queue
+    Ready,
+    /// Sleeping on a `WaitQueue`
+    Blocked,
+    /// Exited, never runs again
+    Zombie,
+}
+
+// `context` has to be the first field, see `Context`
+#[repr(C)]
+pub struct Thread {
+    pub context: Context,
+
+    pub owner: *mut Process,
+
+    pub thread_prev: *mut Thread,
+    pub thread_next: *mut Thread,
+
+    pub sched_prev: *mut Thread,
+    pub sched_next: *mut Thread,
+    /// Next sleeper on the same `WaitQueue`
+    wait_next: *mut Thread,
+
+    state: State,
+    /// CPU whose run queue the thread is on
+    cpu: u32,
+    /// CPUs the thread may run on, bit per CPU ID
+    affinity: u64,
+    /// Thread is being executed by some CPU. Cleared once
+    /// its context is saved, so that other CPUs can pick it
+    running: AtomicBool,
+
+    policy: Policy,
+    /// Real-time priority, 1 (lowest) to 99
+    priority: u8,
+    /// Weight in the fair class, -20 (highest) to 19
+    nice: i8,
+    /// Fair class: CPU time used, scaled by weight. Relative
+    /// to the queue's minimum while not queued
+    vruntime: u64,
+    /// Round-robin: ticks left in the time slice
+    slice: u32,
+}
+
+static LAST_PID: AtomicI32 = AtomicI32::new(0);
+
+fn next_pid() -> i32 {
+    LAST_PID.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+impl Process {
+    pub fn new_kernel() -> Process {
+        println!("Create new empty process");
+        Process {
+            id: 0,  // TODO
+            is_user: false,
+            head: null_mut(),
+            space: unsafe { mem::KERNEL.as_mut() }.unwrap(),
+            regions: RegionList::new(),
+            files: FdTable::new(),
+            cwd: String::from("/"),
+        }
+    }
+
+    pub fn new_user() -> Option<Process> {
+        let space = Space::new_user().ok()?;
+        let id = next_pid();
+
+        println!("Create new user process #{}", id);
+        Some(Process {
+            id,
+            is_user: true,
+            head: null_mut(),
+            space,
+            regions: RegionList::new(),
+            files: FdTable::new(),
+            cwd: String::from("/"),
+        })
+    }
+
+    pub fn spawn(&mut self, entry: usize, arg: usize) -> Option<*mut Thread> {
+        println!("Spawn a thread in process #{}", self.id);
+
+        // TODO: argument
+        let _ = arg;
+        Some(self.add_thread(Context::new_kernel(entry, self.space.physical()), None))
+    }
+
+    /// Starts a ring 3 thread at `entry` with stack pointer
+    /// `stack`, which has to be within one of process regions
+    pub fn spawn_user(&mut self, entry: usize, stack: usize) -> *mut Thread {
+        assert!(self.is_user);
+        println!("Spawn a user thread in process #{}", self.id);
+        self.add_thread(Context::new_user(entry, stack, self.space.physical()), None)
+    }
+
+    /// Duplicates the process along with the calling `thread`,
+    /// which must be inside a syscall. Memory is shared
+    /// copy-on-write. Returns child process ID
+    pub fn fork(&mut self, thread: &Thread) -> Option<i32> {
+        assert!(self.is_user);
+        let space = self.space.fork().ok()?;
+        let context = Context::new_fork(thread.context.syscall_frame(), space.physical());
+
+        let child = Box::into_raw(Box::new(Process {
+            id: next_pid(),
+            is_user: true,
+            head: null_mut(),
+            space,
+            regions: self.regions.clone(),
+            files: self.files.clone(),
+            cwd: self.cwd.clone(),
+        }));
+
+        unsafe {
+            println!("Process #{} forked into #{}", self.id, (*child).id);
+            (*child).add_thread(context, Some(thread));
+            Some((*child).id)
+        }
+    }
+
+    /// Creates a thread and makes it runnable. Scheduling
+    /// parameters are inherited from `parent` if given
+    fn add_thread(&mut self, context: Context, parent: Option<&Thread>) -> *mut Thread {
+        let thread = Box::into_raw(Box::new(Thread::new(self as *mut Process, context)));
+        unsafe {
+            if let Some(parent) = parent {
+                (*thread).policy = parent.policy;
+                (*thread).priority = parent.priority;
+                (*thread).nice = parent.nice;
+                (*thread).affinity = parent.affinity;
+            }
+            (*thread).thread_next = self.head;
+            if !self.head.is_null() {
+                (*self.head).thread_prev = thread;
+            }
+        }
+        self.head = thread;
+        unsafe { (*thread).queue(); }
+
+        thread
+    }
+
+    /// Unlinks an exited thread from the process
+    fn remove_thread(&mut self, thread: &mut Thread) {
+        unsafe {
+            if thread.thread_prev.is_null() {
+                self.head = thread.thread_next;
+            } else {
+                (*thread.thread_prev).thread_next = thread.thread_next;
+            }
+            if !thread.thread_next.is_null() {
+                (*thread.thread_next).thread_prev = thread.thread_prev;
+            }
+        }
+        thread.thread_prev = null_mut();
+        thread.thread_next = null_mut();
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        if self.is_user {
+            println!("Release address space of process #{}", self.id);
+            unsafe { self.space.destroy(); }
+        }
+    }
+}
+
+impl Thread {
+    fn new(owner: *mut Process, context: Context) -> Thread {
+        Thread {
+            context,
+
+            owner,
+
+            thread_next: null_mut(),
+            thread_prev: null_mut(),
+
+            sched_prev: null_mut(),
+            sched_next: null_mut(),
+            wait_next: null_mut(),
+
+            state: State::Ready,
+            cpu: 0,
+            affinity: !0,
+            running: AtomicBool::new(false),
+
+            policy: Policy::Fair,
+            priority: 0,
+            nice: 0,
+            vruntime: 0,
+            slice: 0,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+}
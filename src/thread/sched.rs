@@ -0,0 +1,612 @@
+//! Per-CPU run queues and scheduling classes. Real-time
+//! threads (FIFO and round-robin) always go before fair ones,
+//! which share CPU time according to their nice values
+
+use super::{Context, Process, State, Thread};
+use crate::arch::x86::{cpu, smp};
+use crate::sync::IrqDisable;
+use crate::time;
+use alloc::boxed::Box;
+use core::cmp::max;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Policy {
+    /// Time-sharing, CPU time is divided according to nice values
+    Fair,
+    /// Real-time, runs until it yields or a higher
+    /// priority thread becomes runnable
+    Fifo,
+    /// Real-time, equal priority threads take turns
+    RoundRobin,
+}
+
+pub const MIN_RT_PRIORITY: u8 = 1;
+pub const MAX_RT_PRIORITY: u8 = 99;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Maximum number of CPUs, bounded by affinity mask width
+pub const MAX_CPUS: usize = 64;
+/// Yields between attempts to even out the queues
+const BALANCE_INTERVAL: usize = 16;
+/// Round-robin time slice, in timer ticks
+const RR_SLICE: u32 = 10;
+/// Virtual runtime a nice 0 thread gets charged per tick
+const VRUNTIME_TICK: u64 = 1 << 20;
+
+/// Relative weights of nice levels -20 .. 19, each step is
+/// ~10% of CPU time (same as in Linux)
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548,  7620,  6100,  4904,  3906,
+    3121,  2501,  1991,  1586,  1277,
+    1024,  820,   655,   526,   423,
+    335,   272,   215,   172,   137,
+    110,   87,    70,    56,    45,
+    36,    29,    23,    18,    15,
+];
+const NICE_0_WEIGHT: u64 = 1024;
+
+impl Thread {
+    /// Only stable under the lock of the queue the thread is on,
+    /// another CPU may be linking it in meanwhile
+    fn is_queued(&self) -> bool {
+        !self.sched_next.is_null()
+    }
+
+    fn allowed(&self, cpu: usize) -> bool {
+        self.affinity & (1 << cpu) != 0
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    fn is_realtime(&self) -> bool {
+        self.policy != Policy::Fair
+    }
+
+    /// Whether the current thread stays on the CPU when
+    /// a thread of equal priority is runnable
+    fn keeps_cpu(&self) -> bool {
+        match self.policy {
+            Policy::Fifo        => true,
+            Policy::RoundRobin  => self.slice != 0,
+            Policy::Fair        => false,
+        }
+    }
+
+    /// Whether `self` should run before `other`
+    fn goes_before(&self, other: &Thread) -> bool {
+        match (self.is_realtime(), other.is_realtime()) {
+            (true, true)    => self.priority > other.priority,
+            (true, false)   => true,
+            (false, true)   => false,
+            (false, false)  => self.vruntime < other.vruntime,
+        }
+    }
+
+    /// Mask of CPUs the thread may run on
+    pub fn affinity(&self) -> u64 {
+        self.affinity
+    }
+
+    /// Restricts the thread to CPUs in `mask`. A thread running
+    /// elsewhere moves once it yields
+    pub fn set_affinity(&mut self, mask: u64) {
+        let online = !0u64 >> (64 - smp::cpu_count());
+        assert!(mask & online != 0, "Affinity mask has no online CPUs");
+        self.affinity = mask;
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Real-time priority, 0 for fair class threads
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice
+    }
+
+    /// Changes scheduling class. `priority` has to be within
+    /// `MIN_RT_PRIORITY ..= MAX_RT_PRIORITY` for real-time
+    /// policies and 0 for `Fair`
+    pub fn set_policy(&mut self, policy: Policy, priority: u8) {
+        match policy {
+            Policy::Fair => assert!(priority == 0),
+            _ => assert!(priority >= MIN_RT_PRIORITY && priority <= MAX_RT_PRIORITY),
+        }
+        self.policy = policy;
+        self.priority = priority;
+        self.slice = 0;
+    }
+
+    pub fn set_nice(&mut self, nice: i8) {
+        assert!(nice >= MIN_NICE && nice <= MAX_NICE);
+        self.nice = nice;
+    }
+
+    fn weight(&self) -> u64 {
+        NICE_WEIGHTS[(self.nice - MIN_NICE) as usize]
+    }
+
+    /// Least loaded online CPU the thread is allowed to run on,
+    /// the one it ran on last wins a tie
+    fn pick_cpu(&self) -> usize {
+        let mut best = None;
+        for id in 0 .. smp::cpu_count() as usize {
+            if !self.allowed(id) {
+                continue;
+            }
+            let load = RUN_QUEUES[id].load.load(Ordering::Relaxed);
+            match best {
+                Some((_, best_load)) if best_load < load => (),
+                Some((_, best_load)) if best_load == load && id != self.cpu as usize => (),
+                _ => best = Some((id, load))
+            }
+        }
+        best.expect("Affinity mask has no online CPUs").0
+    }
+
+    /// Puts the thread on a run queue of one of the CPUs
+    /// it's allowed to run on
+    pub fn queue(&mut self) {
+        let _irq = IrqDisable::new();
+        if self.is_queued() {
+            return;
+        }
+
+        let id = self.pick_cpu();
+        let rq = &RUN_QUEUES[id];
+        {
+            let mut queue = rq.queue.lock();
+            self.cpu = id as u32;
+            self.state = State::Ready;
+            rq.push(&mut queue, self);
+        }
+
+        // Idle CPUs don't tick
+        if rq.idle.load(Ordering::Acquire) {
+            smp::kick(id);
+        }
+    }
+
+    /// Removes the thread from its run queue. If it's the current
+    /// one, the caller has to switch away by itself
+    pub fn dequeue(&mut self) {
+        let _irq = IrqDisable::new();
+        loop {
+            let id = self.cpu as usize;
+            let rq = &RUN_QUEUES[id];
+            let mut queue = rq.queue.lock();
+
+            // Could've been stolen by another CPU meanwhile
+            if id != self.cpu as usize {
+                continue;
+            }
+            if self.is_queued() {
+                rq.remove(&mut queue, self);
+            }
+            return;
+        }
+    }
+}
+
+/// Circular list of threads linked through `sched_prev`
+/// and `sched_next`
+struct Queue {
+    head: *mut Thread,
+    len: usize,
+    ticks: usize,
+    /// Fair class threads' virtual runtimes are
+    /// kept relative to this while not queued
+    min_vruntime: u64,
+}
+
+struct RunQueue {
+    queue: Mutex<Queue>,
+    /// Length of the queue, readable without the lock
+    load: AtomicUsize,
+    /// CPU is running its idle thread
+    idle: AtomicBool,
+}
+
+// Threads are only touched with the queue locked
+unsafe impl Send for Queue {}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue {
+            head: null_mut(),
+            len: 0,
+            ticks: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    fn push(&mut self, thread: &mut Thread) {
+        if self.head.is_null() {
+            thread.sched_prev = thread;
+            thread.sched_next = thread;
+
+            self.head = thread;
+        } else {
+            unsafe {
+                let tail = (*self.head).sched_prev;
+
+                (*tail).sched_next = thread;
+                thread.sched_prev = tail;
+                (*self.head).sched_prev = thread;
+                thread.sched_next = self.head;
+            }
+        }
+        thread.vruntime += self.min_vruntime;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, thread: &mut Thread) {
+        let prev = thread.sched_prev;
+        let next = thread.sched_next;
+        let this = thread as *mut Thread;
+
+        if next == this {
+            self.head = null_mut();
+        } else {
+            if self.head == this {
+                self.head = next;
+            }
+            unsafe {
+                (*prev).sched_next = next;
+                (*next).sched_prev = prev;
+            }
+        }
+
+        thread.sched_next = null_mut();
+        thread.sched_prev = null_mut();
+        thread.vruntime = thread.vruntime.saturating_sub(self.min_vruntime);
+        self.len -= 1;
+    }
+
+    /// First thread matching `pred`, starting at `start`
+    /// (or the head if it's null)
+    unsafe fn find<F: Fn(&Thread) -> bool>(&self, start: *mut Thread, pred: F) -> Option<*mut Thread> {
+        let start = if start.is_null() { self.head } else { start };
+        if start.is_null() {
+            return None;
+        }
+
+        let mut thread = start;
+        loop {
+            if pred(&*thread) {
+                return Some(thread);
+            }
+            thread = (*thread).sched_next;
+            if thread == start {
+                return None;
+            }
+        }
+    }
+
+    /// Highest priority thread which can run here. Equal ones
+    /// are taken in list order after `curr`, so they alternate.
+    /// `own` tells `curr` is on this queue, `give_way` makes
+    /// it lose ties regardless of its policy
+    unsafe fn best(&self, curr: *mut Thread, own: bool, give_way: bool) -> Option<*mut Thread> {
+        let start = if own { (*curr).sched_next } else { self.head };
+        if start.is_null() {
+            return None;
+        }
+
+        let mut best: Option<*mut Thread> = None;
+        let mut thread = start;
+        loop {
+            if thread != curr && !(*thread).is_running() {
+                if best.map_or(true, |b| (*thread).goes_before(&*b)) {
+                    best = Some(thread);
+                }
+            }
+            thread = (*thread).sched_next;
+            if thread == start {
+                break;
+            }
+        }
+
+        if own {
+            // Current one wins ties only if it's allowed to
+            let keep = match best {
+                Some(b) => (*curr).goes_before(&*b) ||
+                    (!give_way && (*curr).keeps_cpu() && !(*b).goes_before(&*curr)),
+                None => true
+            };
+            if keep {
+                best = Some(curr);
+            }
+        }
+        best
+    }
+}
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue {
+            queue: Mutex::new(Queue::new()),
+            load: AtomicUsize::new(0),
+            idle: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, queue: &mut Queue, thread: &mut Thread) {
+        queue.push(thread);
+        self.load.store(queue.len, Ordering::Relaxed);
+    }
+
+    fn remove(&self, queue: &mut Queue, thread: &mut Thread) {
+        queue.remove(thread);
+        self.load.store(queue.len, Ordering::Relaxed);
+    }
+}
+
+/// Each CPU switches between threads of its own queue. While
+/// switching, the lock is taken by the outgoing thread and
+/// released by the incoming one
+static RUN_QUEUES: [RunQueue; MAX_CPUS] = [RunQueue::new(); MAX_CPUS];
+
+/// Thread running on this CPU, null before scheduling starts
+#[inline(always)]
+pub fn current() -> *mut Thread {
+    cpu::this().current
+}
+
+fn idle(_: usize) {
+    loop {
+        unsafe { llvm_asm!("sti; hlt"); }
+    }
+}
+
+/// Pulls a thread from the busiest queue if it's noticeably
+/// longer than the `id` one. Only tries the other queue's lock
+/// to not deadlock against a CPU doing the same
+unsafe fn balance(queue: &mut Queue, id: usize) -> bool {
+    let own = RUN_QUEUES[id].load.load(Ordering::Relaxed);
+    let mut busiest = None;
+    for other in 0 .. smp::cpu_count() as usize {
+        let load = RUN_QUEUES[other].load.load(Ordering::Relaxed);
+        if other != id && load > own + 1 && busiest.map_or(true, |(_, max)| load > max) {
+            busiest = Some((other, load));
+        }
+    }
+
+    let victim = match busiest {
+        Some((other, _)) => &RUN_QUEUES[other],
+        None => return false
+    };
+    let mut other = match victim.queue.try_lock() {
+        Some(other) => other,
+        None => return false
+    };
+
+    match other.find(null_mut(), |t| !t.is_running() && t.allowed(id)) {
+        Some(thread) => {
+            victim.remove(&mut other, &mut *thread);
+            (*thread).cpu = id as u32;
+            RUN_QUEUES[id].push(queue, &mut *thread);
+            true
+        },
+        None => false
+    }
+}
+
+/// Next thread to run instead of `curr` (may be `curr` itself)
+/// from the `id` CPU queue, skipping the ones still running
+/// elsewhere. Tries other queues before falling back to the
+/// idle thread
+unsafe fn pick_next(queue: &mut Queue, id: usize, curr: *mut Thread, give_way: bool) -> *mut Thread {
+    queue.ticks += 1;
+    if queue.ticks % BALANCE_INTERVAL == 0 {
+        balance(queue, id);
+    }
+
+    let own = !curr.is_null() && (*curr).is_queued() && (*curr).cpu as usize == id;
+    let next = match queue.best(curr, own, give_way) {
+        Some(next) => next,
+        None if balance(queue, id) => queue.best(curr, own, give_way).unwrap_or(cpu::this().idle),
+        None => cpu::this().idle
+    };
+
+    if (*next).policy == Policy::RoundRobin && (*next).slice == 0 {
+        (*next).slice = RR_SLICE;
+    }
+    if (*next).policy == Policy::Fair && (*next).is_queued() {
+        queue.min_vruntime = max(queue.min_vruntime, (*next).vruntime);
+    }
+    next
+}
+
+/// Stops the tick on CPU `id` if it's going idle and
+/// restarts it otherwise
+fn update_idle(id: usize, next: *mut Thread) {
+    let idle = next == cpu::this().idle;
+    RUN_QUEUES[id].idle.store(idle, Ordering::Release);
+    time::set_cpu_idle(idle);
+}
+
+/// Switches from `curr` to `next`, the CPU's queue has to be
+/// locked and gets unlocked once `next` is running
+unsafe fn switch(curr: *mut Thread, next: *mut Thread) {
+    let cpu = cpu::this();
+    if !curr.is_null() && (*curr).state == State::Running {
+        (*curr).state = State::Ready;
+    }
+    (*next).state = State::Running;
+    (*next).running.store(true, Ordering::Release);
+    cpu.previous = curr;
+    cpu.current = next;
+
+    if curr.is_null() {
+        (*next).context.initial_switch();
+    } else {
+        (*curr).context.switch_to(&mut (*next).context);
+        sched_finish_switch();
+    }
+}
+
+/// Completes a switch on the incoming thread's side: the
+/// previous one may now be picked by other CPUs
+#[no_mangle]
+pub extern "C" fn sched_finish_switch() {
+    let cpu = cpu::this();
+    let id = cpu.id as usize;
+    let prev = cpu.previous;
+    unsafe {
+        if !prev.is_null() {
+            (*prev).running.store(false, Ordering::Release);
+            cpu.previous = null_mut();
+        }
+        RUN_QUEUES[id].queue.force_unlock();
+
+        // Queued elsewhere while still running here, the other CPU
+        // may have skipped it and gone idle. Taking its lock orders
+        // this against its `pick_next` and `update_idle`
+        let other = if prev.is_null() { id } else { (*prev).cpu as usize };
+        if other != id {
+            let rq = &RUN_QUEUES[other];
+            drop(rq.queue.lock());
+            if rq.idle.load(Ordering::Acquire) {
+                smp::kick(other);
+            }
+        }
+
+        if !prev.is_null() && (*prev).state == State::Zombie {
+            reap(prev);
+        }
+    }
+}
+
+/// Frees an exited thread, nothing runs on its stack anymore.
+/// User processes go away along with their last thread
+unsafe fn reap(thread: *mut Thread) {
+    let owner = (*thread).owner;
+    (*owner).remove_thread(&mut *thread);
+    drop(Box::from_raw(thread));
+
+    if (*owner).is_user && (*owner).head.is_null() {
+        println!("Process #{} exited", (*owner).id);
+        drop(Box::from_raw(owner));
+    }
+}
+
+/// Starts scheduling on the calling CPU
+pub unsafe fn enter() -> ! {
+    let cpu = cpu::this();
+    let id = cpu.id as usize;
+    assert!(id < MAX_CPUS);
+
+    let owner = Box::leak(Box::new(Process::new_kernel()));
+    let context = Context::new_kernel(idle as usize, owner.space.physical());
+    cpu.idle = Box::into_raw(Box::new(Thread::new(owner, context)));
+    (*cpu.idle).cpu = id as u32;
+    (*cpu.idle).affinity = 1 << id;
+
+    let _irq = IrqDisable::new();
+    let mut queue = RUN_QUEUES[id].queue.lock();
+    let next = pick_next(&mut queue, id, null_mut(), false);
+    update_idle(id, next);
+    core::mem::forget(queue);
+    switch(null_mut(), next);
+    unreachable!();
+}
+
+/// Removes current thread from scheduling and switches
+/// to the next one, which frees it
+pub unsafe fn exit_current() -> ! {
+    let curr = current();
+    assert!(!curr.is_null());
+    println!("Thread {:p} of process #{} exited", curr, (*(*curr).owner).id);
+
+    (*curr).state = State::Zombie;
+    (*curr).dequeue();
+    r#yield();
+    unreachable!();
+}
+
+/// Takes the current thread off its run queue, it keeps running
+/// until it yields. Unless woken up before that, the thread
+/// won't be picked again
+pub(super) unsafe fn block_current() {
+    let curr = current();
+    assert!(!curr.is_null());
+    (*curr).state = State::Blocked;
+    (*curr).dequeue();
+}
+
+/// Makes a blocked thread runnable, `false` if it wasn't blocked
+pub(super) fn wake(thread: &mut Thread) -> bool {
+    if thread.state != State::Blocked {
+        return false;
+    }
+    thread.queue();
+    true
+}
+
+/// Charges the current thread for a timer tick and
+/// preempts it if something more important can run
+pub unsafe fn tick() {
+    let curr = current();
+    if curr.is_null() {
+        return;
+    }
+
+    match (*curr).policy {
+        Policy::Fair        => (*curr).vruntime += VRUNTIME_TICK * NICE_0_WEIGHT / (*curr).weight(),
+        Policy::RoundRobin  => (*curr).slice = (*curr).slice.saturating_sub(1),
+        Policy::Fifo        => (),
+    }
+    schedule(false);
+}
+
+/// Switches to a more important thread if there's one,
+/// without charging the current one
+pub unsafe fn preempt() {
+    schedule(false);
+}
+
+/// Gives up the CPU, to threads of equal priority too
+pub unsafe fn r#yield() {
+    schedule(true);
+}
+
+unsafe fn schedule(give_way: bool) {
+    let curr = current();
+    if curr.is_null() {
+        return;
+    }
+
+    let _irq = IrqDisable::new();
+    let id = cpu::this().id as usize;
+
+    // Affinity has changed, the thread keeps running
+    // here until switched away from
+    if (*curr).is_queued() && !(*curr).allowed(id) {
+        (*curr).dequeue();
+        (*curr).queue();
+    }
+
+    let mut queue = RUN_QUEUES[id].queue.lock();
+    let next = pick_next(&mut queue, id, curr, give_way);
+    update_idle(id, next);
+    if next == curr {
+        // Possibly woken up before getting to switch away
+        (*curr).state = State::Running;
+        return;
+    }
+    core::mem::forget(queue);
+    switch(curr, next);
+}
@@ -0,0 +1,175 @@
+//! Queues of threads sleeping until some event happens
+
+use super::{sched, Thread};
+use crate::sync::IrqDisable;
+use crate::time;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// FIFO list linked through `Thread::wait_next`
+struct List {
+    head: *mut Thread,
+    tail: *mut Thread,
+}
+
+pub struct WaitQueue {
+    list: Mutex<List>,
+}
+
+// Threads are only touched with the list locked
+unsafe impl Send for List {}
+
+impl List {
+    fn push(&mut self, thread: &mut Thread) {
+        thread.wait_next = null_mut();
+        if self.tail.is_null() {
+            self.head = thread;
+        } else {
+            unsafe { (*self.tail).wait_next = thread; }
+        }
+        self.tail = thread;
+    }
+
+    fn pop(&mut self) -> Option<&'static mut Thread> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let thread = unsafe { &mut *self.head };
+        self.head = thread.wait_next;
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+        thread.wait_next = null_mut();
+        Some(thread)
+    }
+
+    /// Unlinks `thread`, `false` if it's not on the list
+    fn remove(&mut self, thread: *mut Thread) -> bool {
+        let mut prev: *mut Thread = null_mut();
+        let mut curr = self.head;
+
+        while !curr.is_null() {
+            let next = unsafe { (*curr).wait_next };
+            if curr == thread {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).wait_next = next; }
+                }
+                if self.tail == curr {
+                    self.tail = prev;
+                }
+                unsafe { (*curr).wait_next = null_mut(); }
+                return true;
+            }
+            prev = curr;
+            curr = next;
+        }
+        false
+    }
+}
+
+/// Sleeper to take off `queue` when its timeout expires
+struct Timeout {
+    queue: *const WaitQueue,
+    thread: *mut Thread,
+    expired: AtomicBool,
+}
+
+fn timeout_expired(arg: usize) {
+    let timeout = unsafe { &*(arg as *const Timeout) };
+    let queue = unsafe { &*timeout.queue };
+
+    // Not there if woken up just before
+    if queue.list.lock().remove(timeout.thread) {
+        timeout.expired.store(true, Ordering::Release);
+        sched::wake(unsafe { &mut *timeout.thread });
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            list: Mutex::new(List {
+                head: null_mut(),
+                tail: null_mut(),
+            }),
+        }
+    }
+
+    /// Sleeps until woken up by `wake_one` or `wake_all`
+    pub fn wait(&self) {
+        self.wait_then(|| ());
+    }
+
+    /// Sleeps until woken up, calling `f` once the thread is on
+    /// the queue but before switching away. Wakeups caused by
+    /// `f` (e.g. releasing a lock) thus can't get lost
+    pub fn wait_then<F: FnOnce()>(&self, f: F) {
+        let _irq = IrqDisable::new();
+        let curr = super::current();
+        assert!(!curr.is_null(), "Can't sleep before scheduling starts");
+
+        {
+            let mut list = self.list.lock();
+            list.push(unsafe { &mut *curr });
+            unsafe { sched::block_current(); }
+        }
+        f();
+
+        unsafe { sched::r#yield(); }
+    }
+
+    /// Sleeps until woken up or until `ns` nanoseconds pass,
+    /// returns `false` in the latter case
+    pub fn wait_timeout(&self, ns: u64) -> bool {
+        let timeout = Timeout {
+            queue: self,
+            thread: super::current(),
+            expired: AtomicBool::new(false),
+        };
+        let mut timer = None;
+
+        self.wait_then(|| {
+            timer = Some(time::add_oneshot(ns, timeout_expired, &timeout as *const _ as usize));
+        });
+
+        // Makes sure the callback is done with `timeout`
+        time::cancel(timer.unwrap());
+        !timeout.expired.load(Ordering::Acquire)
+    }
+
+    /// Wakes the longest sleeping thread, `false` if there's none
+    pub fn wake_one(&self) -> bool {
+        let _irq = IrqDisable::new();
+        let thread = self.list.lock().pop();
+        match thread {
+            Some(thread) => sched::wake(thread),
+            None => false
+        }
+    }
+
+    /// Wakes all the sleeping threads, returns how many
+    pub fn wake_all(&self) -> usize {
+        let _irq = IrqDisable::new();
+        // Threads going back to sleep right away shouldn't
+        // get woken again
+        let mut list = {
+            let mut list = self.list.lock();
+            let taken = List { head: list.head, tail: list.tail };
+            list.head = null_mut();
+            list.tail = null_mut();
+            taken
+        };
+
+        let mut count = 0;
+        while let Some(thread) = list.pop() {
+            if sched::wake(thread) {
+                count += 1;
+            }
+        }
+        count
+    }
+}
@@ -0,0 +1,141 @@
+//! Calendar date and time, UTC
+
+use core::fmt;
+
+pub const SECS_PER_DAY: u64 = 86400;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DateTime {
+    pub year:   u32,
+    /// 1 .. 12
+    pub month:  u8,
+    /// 1 .. 31
+    pub day:    u8,
+    pub hour:   u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days since 1970-01-01, the year starts in March so
+/// that the leap day is the last one
+fn days_from_civil(year: u32, month: u8, day: u8) -> u64 {
+    let year = if month <= 2 { year as u64 - 1 } else { year as u64 };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month as u64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn is_leap(year: u32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u32, month: u8) -> u8 {
+    match month {
+        2               => if is_leap(year) { 29 } else { 28 },
+        4 | 6 | 9 | 11  => 30,
+        _               => 31,
+    }
+}
+
+fn civil_from_days(days: u64) -> (u32, u8, u8) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400) as u32 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Whether all fields are in range, dates before the
+    /// Unix epoch aren't supported
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970 &&
+        self.month >= 1 && self.month <= 12 &&
+        self.day >= 1 && self.day <= days_in_month(self.year, self.month) &&
+        self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+
+    /// Seconds since the Unix epoch, the date has to be valid
+    pub fn to_unix(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(secs: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let time = secs % SECS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour:   (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(year: u32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime { year, month, day, hour, minute, second }
+    }
+
+    #[test]
+    fn to_unix() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).to_unix(), 0);
+        assert_eq!(date(2000, 2, 29, 12, 0, 0).to_unix(), 951825600);
+        assert_eq!(date(2020, 12, 31, 23, 59, 59).to_unix(), 1609459199);
+        assert_eq!(date(2100, 3, 1, 0, 0, 0).to_unix(), 4107542400);
+    }
+
+    #[test]
+    fn from_unix() {
+        assert_eq!(DateTime::from_unix(0), date(1970, 1, 1, 0, 0, 0));
+        assert_eq!(DateTime::from_unix(951825600), date(2000, 2, 29, 12, 0, 0));
+        assert_eq!(DateTime::from_unix(1609459199), date(2020, 12, 31, 23, 59, 59));
+        assert_eq!(DateTime::from_unix(4107542400), date(2100, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn is_valid() {
+        assert!(date(1970, 1, 1, 0, 0, 0).is_valid());
+        assert!(date(2000, 2, 29, 23, 59, 59).is_valid());
+        assert!(!date(1900, 2, 29, 0, 0, 0).is_valid());
+        assert!(!date(2021, 2, 29, 0, 0, 0).is_valid());
+        assert!(!date(1969, 12, 31, 0, 0, 0).is_valid());
+        assert!(!date(2020, 1, 0, 0, 0, 0).is_valid());
+        assert!(!date(2020, 0, 1, 0, 0, 0).is_valid());
+        assert!(!date(2020, 13, 1, 0, 0, 0).is_valid());
+        assert!(!date(2020, 4, 31, 0, 0, 0).is_valid());
+        assert!(!date(2020, 1, 1, 24, 0, 0).is_valid());
+        assert!(!date(2020, 1, 1, 0, 60, 0).is_valid());
+        assert!(!date(2020, 1, 1, 0, 0, 60).is_valid());
+    }
+
+    #[test]
+    fn round_trip() {
+        for secs in (0 .. 200 * 366 * SECS_PER_DAY).step_by(7 * 3607) {
+            assert_eq!(DateTime::from_unix(secs).to_unix(), secs);
+        }
+    }
+}
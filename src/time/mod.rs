@@ -0,0 +1,182 @@
+//! Timekeeping: monotonic and realtime clocks, kernel timers and
+//! the per-CPU scheduler tick. A CPU with nothing to run stops ticking and
+//! only wakes up for the next timer (or when kicked by another
+//! CPU which gave it a thread)
+
+use crate::arch::x86::{cpu, tsc};
+use crate::dev::x86::{apic, hpet, pic, pit};
+use crate::sync::IrqDisable;
+use crate::thread::{MAX_CPUS, WaitQueue};
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+pub mod timer;
+pub use timer::{TimerId, add_at, add_oneshot, add_periodic, cancel};
+pub mod date;
+pub use date::DateTime;
+
+pub const NSEC_PER_SEC: u64     = 1000000000;
+pub const NSEC_PER_MSEC: u64    = 1000000;
+pub const NSEC_PER_USEC: u64    = 1000;
+
+/// Scheduler tick rate while a CPU has work to do
+pub const HZ: u32 = 1000;
+/// Longest an idle CPU sleeps if there are no timers,
+/// so that it still gets to balance run queues
+const MAX_IDLE_NS: u64 = NSEC_PER_SEC;
+
+/// Free-running counter the monotonic clock is derived from
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    fn read(&self) -> u64;
+    /// Counter rate, Hz
+    fn frequency(&self) -> u64;
+}
+
+struct Clock {
+    source: Option<&'static dyn ClockSource>,
+    /// Counter value and clock time when the source got selected
+    base_count: u64,
+    base_ns: u64,
+}
+
+impl Clock {
+    fn now(&self) -> u64 {
+        match self.source {
+            Some(source) => {
+                let count = source.read().wrapping_sub(self.base_count) as u128;
+                self.base_ns + (count * NSEC_PER_SEC as u128 / source.frequency() as u128) as u64
+            },
+            None => 0
+        }
+    }
+}
+
+static CLOCK: Mutex<Clock> = Mutex::new(Clock {
+    source: None,
+    base_count: 0,
+    base_ns: 0,
+});
+/// Realtime clock time when the monotonic clock started, ns
+static REALTIME_BASE: AtomicU64 = AtomicU64::new(0);
+/// Whether `REALTIME_BASE` has been set
+static REALTIME_SET: AtomicBool = AtomicBool::new(false);
+/// CPUs whose timer is in one-shot mode
+static TICKLESS: [AtomicBool; MAX_CPUS] = [AtomicBool::new(false); MAX_CPUS];
+
+/// Nanoseconds since the clock got initialized
+pub fn monotonic() -> u64 {
+    let _irq = IrqDisable::new();
+    CLOCK.lock().now()
+}
+
+/// Nanoseconds since the Unix epoch
+pub fn realtime() -> u64 {
+    REALTIME_BASE.load(Ordering::Relaxed) + monotonic()
+}
+
+/// Sets the realtime clock to `ns` since the Unix epoch
+pub fn set_realtime(ns: u64) {
+    REALTIME_BASE.store(ns.saturating_sub(monotonic()), Ordering::Relaxed);
+    REALTIME_SET.store(true, Ordering::Release);
+}
+
+/// Like `realtime`, but gives up instead of spinning if the
+/// clock is locked, so that it's safe to use for logging. None
+/// until the realtime clock is set
+pub fn try_realtime() -> Option<u64> {
+    if !REALTIME_SET.load(Ordering::Acquire) {
+        return None;
+    }
+    let _irq = IrqDisable::new();
+    let now = CLOCK.try_lock()?.now();
+    Some(REALTIME_BASE.load(Ordering::Relaxed) + now)
+}
+
+/// Switches the monotonic clock to `source`, the clock
+/// continues from where the old source left it
+pub fn set_source(source: &'static dyn ClockSource) {
+    {
+        let _irq = IrqDisable::new();
+        let mut clock = CLOCK.lock();
+        let now = clock.now();
+
+        clock.base_count = source.read();
+        clock.base_ns = now;
+        clock.source = Some(source);
+    }
+    println!("Clock source: {}", source.name());
+}
+
+/// Called on every timer interrupt
+pub fn tick() {
+    timer::expire(monotonic());
+}
+
+/// Programs the CPU's timer according to whether it
+/// has any work to do. Called by the scheduler
+pub fn set_cpu_idle(idle: bool) {
+    // PIT keeps ticking
+    if pic::is_active() {
+        return;
+    }
+    let tickless = &TICKLESS[cpu::this().id as usize];
+
+    if idle {
+        let now = monotonic();
+        let deadline = min(timer::next_deadline().unwrap_or(!0), now + MAX_IDLE_NS);
+        apic::timer_oneshot(deadline.saturating_sub(now));
+        tickless.store(true, Ordering::Relaxed);
+    } else if tickless.load(Ordering::Relaxed) {
+        apic::timer_periodic(HZ);
+        tickless.store(false, Ordering::Relaxed);
+    }
+}
+
+fn wake_sleeper(queue: usize) {
+    unsafe { &*(queue as *const WaitQueue) }.wake_all();
+}
+
+/// Puts the current thread to sleep until `deadline`
+/// of the monotonic clock
+pub fn sleep_until(deadline: u64) {
+    let queue = WaitQueue::new();
+    while monotonic() < deadline {
+        queue.wait_then(|| {
+            add_at(deadline, 0, wake_sleeper, &queue as *const _ as usize);
+        });
+    }
+}
+
+pub fn sleep(ns: u64) {
+    sleep_until(monotonic().saturating_add(ns));
+}
+
+/// Starts the tick on this CPU, after `init` on the BSP
+pub fn init_cpu() {
+    if pic::is_active() {
+        pit::start_periodic(HZ);
+        pic::set_masked(0, false);
+    } else {
+        apic::timer_periodic(HZ);
+    }
+}
+
+/// Calibrates timers and starts the monotonic clock. HPET is
+/// the reference if present, TSC is preferred as the clock
+/// source unless it may change rate
+pub fn init() {
+    timer::init();
+    let delay: fn(u32) = if hpet::get().is_some() { hpet::delay_us } else { pit::delay_us };
+    let tsc = tsc::calibrate(delay);
+
+    match hpet::clock_source() {
+        Some(hpet) if !tsc::is_invariant() => set_source(hpet),
+        _ => set_source(tsc)
+    }
+    if !pic::is_active() {
+        apic::calibrate_timer(delay);
+    }
+    init_cpu();
+}
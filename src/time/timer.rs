@@ -0,0 +1,258 @@
+//! One-shot and periodic kernel timers. Callbacks are run
+//! from the timer interrupt, with interrupts disabled
+
+use super::monotonic;
+use crate::arch::x86::cpu;
+use crate::sync::IrqDisable;
+use crate::thread::MAX_CPUS;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub type Callback = fn(usize);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: u64,
+    /// Monotonic clock time, ns
+    deadline: u64,
+    /// 0 for one-shot timers
+    period: u64,
+    callback: Callback,
+    arg: usize,
+}
+
+/// Timers expected to be pending at once, more only
+/// grow the heap
+const CAPACITY: usize = 256;
+
+/// Pending timers, a binary min-heap by deadline
+static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+static LAST_ID: AtomicU64 = AtomicU64::new(0);
+/// Timer whose callback each CPU is running, 0 if none
+static RUNNING: [AtomicU64; MAX_CPUS] = [AtomicU64::new(0); MAX_CPUS];
+
+impl Timer {
+    /// Timers with the same deadline fire in the order
+    /// they were added
+    fn before(&self, other: &Timer) -> bool {
+        (self.deadline, self.id) < (other.deadline, other.id)
+    }
+}
+
+fn sift_up(timers: &mut [Timer], mut pos: usize) {
+    while pos > 0 {
+        let parent = (pos - 1) / 2;
+        if !timers[pos].before(&timers[parent]) {
+            break;
+        }
+        timers.swap(pos, parent);
+        pos = parent;
+    }
+}
+
+fn sift_down(timers: &mut [Timer], mut pos: usize) {
+    loop {
+        let mut first = pos;
+        for child in (2 * pos + 1 ..= 2 * pos + 2).filter(|&child| child < timers.len()) {
+            if timers[child].before(&timers[first]) {
+                first = child;
+            }
+        }
+        if first == pos {
+            break;
+        }
+        timers.swap(pos, first);
+        pos = first;
+    }
+}
+
+fn insert(timers: &mut Vec<Timer>, timer: Timer) {
+    timers.push(timer);
+    let last = timers.len() - 1;
+    sift_up(timers, last);
+}
+
+fn remove(timers: &mut Vec<Timer>, pos: usize) -> Timer {
+    let timer = timers.swap_remove(pos);
+    // The last timer took its place
+    if pos < timers.len() {
+        sift_down(timers, pos);
+        sift_up(timers, pos);
+    }
+    timer
+}
+
+/// Preallocates the heap, so that adding timers doesn't
+/// allocate in the common case
+pub(super) fn init() {
+    let _irq = IrqDisable::new();
+    TIMERS.lock().reserve(CAPACITY);
+}
+
+/// Calls `callback(arg)` once the monotonic clock reaches
+/// `deadline`, then every `period` ns unless it's 0
+pub fn add_at(deadline: u64, period: u64, callback: Callback, arg: usize) -> TimerId {
+    let id = LAST_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    let _irq = IrqDisable::new();
+    insert(&mut TIMERS.lock(), Timer { id, deadline, period, callback, arg });
+    TimerId(id)
+}
+
+/// Calls `callback(arg)` after `delay` ns
+pub fn add_oneshot(delay: u64, callback: Callback, arg: usize) -> TimerId {
+    add_at(monotonic().saturating_add(delay), 0, callback, arg)
+}
+
+/// Calls `callback(arg)` every `period` ns
+pub fn add_periodic(period: u64, callback: Callback, arg: usize) -> TimerId {
+    assert!(period != 0);
+    add_at(monotonic().saturating_add(period), period, callback, arg)
+}
+
+/// Removes a timer. Returns `false` if it has already fired (or
+/// been cancelled), in which case its callback is guaranteed to
+/// have finished unless called from the callback itself
+pub fn cancel(id: TimerId) -> bool {
+    let removed = {
+        let _irq = IrqDisable::new();
+        let mut timers = TIMERS.lock();
+        match timers.iter().position(|t| t.id == id.0) {
+            Some(pos) => {
+                remove(&mut timers, pos);
+                true
+            },
+            None => false
+        }
+    };
+
+    let this = cpu::this().id as usize;
+    for (cpu, running) in RUNNING.iter().enumerate() {
+        while cpu != this && running.load(Ordering::Acquire) == id.0 {
+            unsafe { llvm_asm!("pause"); }
+        }
+    }
+    removed
+}
+
+/// Deadline of the earliest timer
+pub fn next_deadline() -> Option<u64> {
+    let _irq = IrqDisable::new();
+    TIMERS.lock().first().map(|t| t.deadline)
+}
+
+/// Runs callbacks of the timers which are due at `now`
+pub(super) fn expire(now: u64) {
+    let running = &RUNNING[cpu::this().id as usize];
+
+    loop {
+        let (callback, arg) = {
+            let mut timers = TIMERS.lock();
+            let timer = match timers.first() {
+                Some(timer) if timer.deadline <= now => remove(&mut timers, 0),
+                _ => return
+            };
+
+            if timer.period != 0 {
+                // Missed periods are skipped
+                let mut next = timer.deadline + timer.period;
+                if next <= now {
+                    next = now + timer.period;
+                }
+                insert(&mut timers, Timer { deadline: next, ..timer });
+            }
+            running.store(timer.id, Ordering::Release);
+            (timer.callback, timer.arg)
+        };
+
+        callback(arg);
+        running.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{insert, remove, Timer};
+    use std::vec::Vec;
+
+    fn nop(_: usize) {}
+
+    fn timer(id: u64, deadline: u64, period: u64) -> Timer {
+        Timer { id, deadline, period, callback: nop, arg: 0 }
+    }
+
+    fn check(timers: &[Timer]) {
+        for pos in 1 .. timers.len() {
+            assert!(!timers[pos].before(&timers[(pos - 1) / 2]));
+        }
+    }
+
+    /// IDs in the order the timers expire
+    fn drain(timers: &mut Vec<Timer>) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while !timers.is_empty() {
+            ids.push(remove(timers, 0).id);
+            check(timers);
+        }
+        ids
+    }
+
+    #[test]
+    fn ordering() {
+        let mut timers = Vec::new();
+        for (id, &deadline) in [50, 10, 40, 20, 30, 5, 45].iter().enumerate() {
+            insert(&mut timers, timer(id as u64 + 1, deadline, 0));
+            check(&timers);
+        }
+        assert_eq!(timers[0].deadline, 5);
+        assert_eq!(drain(&mut timers), [6, 2, 4, 5, 3, 7, 1]);
+    }
+
+    #[test]
+    fn equal_deadlines() {
+        let mut timers = Vec::new();
+        for &id in [4, 1, 6, 3, 2, 5].iter() {
+            insert(&mut timers, timer(id, 100, 0));
+        }
+        insert(&mut timers, timer(7, 50, 0));
+        assert_eq!(drain(&mut timers), [7, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn remove_middle() {
+        let mut timers = Vec::new();
+        for id in 1 ..= 16 {
+            insert(&mut timers, timer(id, 1000 - id * 10, 0));
+        }
+        for &id in [9, 1, 16].iter() {
+            let pos = timers.iter().position(|t| t.id == id).unwrap();
+            assert_eq!(remove(&mut timers, pos).id, id);
+            check(&timers);
+        }
+        assert_eq!(drain(&mut timers), [15, 14, 13, 12, 11, 10, 8, 7, 6, 5, 4, 3, 2]);
+    }
+
+    #[test]
+    fn periodic_reinsert() {
+        let mut timers = Vec::new();
+        insert(&mut timers, timer(1, 10, 10));
+        insert(&mut timers, timer(2, 15, 0));
+        insert(&mut timers, timer(3, 20, 0));
+
+        // As `expire` does
+        let mut fired = Vec::new();
+        while timers[0].deadline <= 30 {
+            let t = remove(&mut timers, 0);
+            fired.push(t.id);
+            if t.period != 0 {
+                insert(&mut timers, Timer { deadline: t.deadline + t.period, ..t });
+            }
+            check(&timers);
+        }
+        assert_eq!(fired, [1, 2, 1, 3, 1]);
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].deadline, 40);
+    }
+}